@@ -8,7 +8,7 @@ use cc::Build;
 fn main() {
     let out_dir = env::var("OUT_DIR").expect("No out dir");
     let dest_path = Path::new(&out_dir);
-    let mut f = File::create(&dest_path.join("memory.x"))
+    let mut f = File::create(dest_path.join("memory.x"))
         .expect("Could not create file");
 
     f.write_all(include_bytes!("memory.x"))
@@ -19,9 +19,15 @@ fn main() {
     println!("cargo:rerun-if-changed=memory.x");
     println!("cargo:rerun-if-changed=build.rs");
 
-    // assemble the `asm.s` file
-    Build::new().file("src/mode_change.S").compile("asm"); // <- NEW!
+    // assemble the `asm.s` file (not for host unit tests, which can't use it anyway)
+    if env::var("CARGO_CFG_TARGET_ARCH").is_ok_and(|arch| arch.starts_with("riscv")) {
+        Build::new()
+            .file("src/mode_change.S")
+            .file("src/trap.S")
+            .compile("asm"); // <- NEW!
+    }
 
     // rebuild if `mode_changes` changed
     println!("cargo:rerun-if-changed=src/mode_change.S"); // <- NEW!
+    println!("cargo:rerun-if-changed=src/trap.S");
 }
\ No newline at end of file
@@ -50,8 +50,8 @@ SECTIONS
     KEEP(*(.init));
     KEEP(*(.init.rust));
     . = ALIGN(4);
-    (*(.trap));
-    (*(.trap.rust));
+    *(.trap);
+    *(.trap.rust);
 
     *(.text .text.*);
     _etext = .;
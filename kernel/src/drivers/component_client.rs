@@ -0,0 +1,171 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::{Mutex, RwLock};
+use crate::drivers::component_fifo::{ComponentFifo, Invoke};
+use crate::peripherals::basic_fifo::BasicFIFO;
+use crate::peripherals::taggedbinary::{TaggedBinary, DecodeError};
+use crate::timer::Deadline;
+
+/// How long the host gets to start answering a request
+const RESPONSE_TIMEOUT_MS: u64 = 5_000;
+
+#[derive(Clone, Debug)]
+pub enum ComponentError {
+    /// The host reported an error; this is its message, if it sent one
+    Remote(String),
+    /// The response couldn't be decoded
+    Decode(DecodeError),
+    /// The response decoded, but wasn't shaped like a response
+    Protocol(&'static str),
+    /// No response before RESPONSE_TIMEOUT_MS
+    Timeout,
+    /// An earlier call timed out and its late response hasn't turned up to be discarded yet
+    OutOfSync,
+}
+
+impl From<DecodeError> for ComponentError {
+    fn from(e: DecodeError) -> Self {
+        ComponentError::Decode(e)
+    }
+}
+
+impl fmt::Display for ComponentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ComponentError::Remote(msg) => write!(f, "component error: {}", msg),
+            ComponentError::Decode(e) => write!(f, "bad component response: {:?}", e),
+            ComponentError::Protocol(msg) => write!(f, "component protocol error: {}", msg),
+            ComponentError::Timeout => write!(f, "component call timed out"),
+            ComponentError::OutOfSync => write!(f, "component client waiting on a timed out response"),
+        }
+    }
+}
+
+pub struct ComponentClient {
+    fifo: ComponentFifo,
+    /// A call timed out, so whatever turns up in the FIFO next is its response, not ours
+    out_of_sync: bool,
+}
+
+static GLOBAL_COMPONENT_CLIENT: RwLock<Option<ComponentClient>> = RwLock::new(None);
+
+/// Values dropped while the client was busy; destroyed the next time someone uses it
+static PENDING_DESTROYS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+impl ComponentClient {
+    pub fn create_global() {
+        let current = GLOBAL_COMPONENT_CLIENT.upgradeable_read();
+        if current.is_some() {
+            panic!("Global component client already initialized")
+        }
+        let mut write = current.upgrade();
+        write.replace(ComponentClient {
+            fifo: ComponentFifo::new(BasicFIFO::component_fifo()),
+            out_of_sync: false,
+        });
+    }
+
+    pub fn get_global<F, T>(f: F) -> T where F: Fn(&mut ComponentClient) -> T {
+        let mut lock = GLOBAL_COMPONENT_CLIENT.write();
+        let client = lock.as_mut().unwrap();
+        client.destroy_pending();
+        f(client)
+    }
+
+    /** Called when the last RemoteValue for `value` drops */
+    pub fn release_value(value: u32) {
+        // The drop might happen inside get_global, so don't wait on the lock
+        if let Some(mut lock) = GLOBAL_COMPONENT_CLIENT.try_write() {
+            if let Some(client) = lock.as_mut() {
+                // Nothing useful to do if this fails
+                let _ = client.destroy_value(value);
+                return;
+            }
+        }
+        PENDING_DESTROYS.lock().push(value);
+    }
+}
+
+impl ComponentClient {
+    /** Call `method` on the component at `address`, returning its results. Any values are owned by the caller. */
+    pub fn invoke(&mut self, address: &str, method: &str, args: &[TaggedBinary])
+                  -> Result<Vec<TaggedBinary<'static>>, ComponentError> {
+        self.resync()?;
+        self.fifo.write_invoke(&Invoke { address, method, args });
+        self.wait_response()?;
+        let mut response: Vec<TaggedBinary<'static>> = self.fifo.read_until_end()?
+            .into_iter()
+            .map(TaggedBinary::adopt_values)
+            .collect();
+        if response.is_empty() {
+            return Err(ComponentError::Protocol("missing error flag"));
+        }
+        return match response.remove(0) {
+            TaggedBinary::Int8(0) => Ok(response),
+            TaggedBinary::Int8(_) => Err(ComponentError::Remote(ComponentClient::error_message(&response))),
+            _ => Err(ComponentError::Protocol("missing error flag"))
+        };
+    }
+
+    /** Raw COMPONENT_ID_LIST: alternating type and address strings */
+    pub fn list(&mut self, filter: Option<&str>) -> Result<Vec<TaggedBinary<'static>>, ComponentError> {
+        self.resync()?;
+        self.fifo.write_list(filter);
+        self.wait_response()?;
+        return Ok(self.fifo.read_until_end()?);
+    }
+
+    pub fn destroy_value(&mut self, value: u32) -> Result<(), ComponentError> {
+        self.resync()?;
+        self.fifo.write_destroy_value(value);
+        self.wait_response()?;
+        let response = self.fifo.read_until_end()?;
+        return match response.first() {
+            Some(TaggedBinary::Int8(0)) => Ok(()),
+            Some(TaggedBinary::Int8(_)) => Err(ComponentError::Remote(ComponentClient::error_message(&response[1..]))),
+            _ => Err(ComponentError::Protocol("missing error flag"))
+        };
+    }
+
+    fn wait_response(&mut self) -> Result<(), ComponentError> {
+        if self.fifo.wait_response(Deadline::after_ms(RESPONSE_TIMEOUT_MS)) {
+            return Ok(());
+        }
+        self.out_of_sync = true;
+        return Err(ComponentError::Timeout);
+    }
+
+    /** After a timeout, drop the late response before sending anything else. Fails until it arrives. */
+    fn resync(&mut self) -> Result<(), ComponentError> {
+        if !self.out_of_sync {
+            return Ok(());
+        }
+        if !self.fifo.read_ready() {
+            return Err(ComponentError::OutOfSync);
+        }
+        self.fifo.discard_pending();
+        self.out_of_sync = false;
+        return Ok(());
+    }
+
+    fn destroy_pending(&mut self) {
+        let pending = core::mem::take(&mut *PENDING_DESTROYS.lock());
+        for value in pending {
+            let _ = self.destroy_value(value);
+        }
+    }
+
+    fn error_message(values: &[TaggedBinary]) -> String {
+        match values.first() {
+            Some(TaggedBinary::Bytes(msg)) => String::from_utf8_lossy(msg).into_owned(),
+            Some(other) => format!("{:?}", other),
+            None => String::new()
+        }
+    }
+}
+
+// The FIFO is only ever touched through the global lock
+unsafe impl Sync for ComponentClient {}
+
+unsafe impl Send for ComponentClient {}
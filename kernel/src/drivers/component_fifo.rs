@@ -1,21 +1,103 @@
+use alloc::vec::Vec;
 use crate::peripherals::basic_fifo::BasicFIFO;
+use crate::peripherals::stream::InStream;
+use crate::peripherals::taggedbinary::{TaggedBinary, DecodeError};
+use crate::timer::Deadline;
 
-struct Invoke {
+pub const COMPONENT_ID_INVOKE: u8 = 0x00;
+pub const COMPONENT_ID_LIST: u8 = 0x01;
+pub const COMPONENT_ID_DESTROY_VALUE: u8 = 0x02;
 
+pub struct Invoke<'a> {
+    pub address: &'a str,
+    pub method: &'a str,
+    pub args: &'a [TaggedBinary<'a>],
 }
 
-struct ComponentFifo {
+/** Raw request/response framing for the component FIFO. See ComponentClient for the nice version. */
+pub struct ComponentFifo {
     fifo: BasicFIFO
 }
 
 impl ComponentFifo {
-    fn new(fifo: BasicFIFO) -> ComponentFifo {
+    pub fn new(fifo: BasicFIFO) -> ComponentFifo {
         ComponentFifo {
             fifo
         }
     }
 
-    fn write_invoke() {
+    pub fn write_invoke(&mut self, invoke: &Invoke) {
+        TaggedBinary::Int8(COMPONENT_ID_INVOKE).write_to(&mut self.fifo);
+        TaggedBinary::string(invoke.address).write_to(&mut self.fifo);
+        TaggedBinary::string(invoke.method).write_to(&mut self.fifo);
+        for arg in invoke.args {
+            arg.write_to(&mut self.fifo);
+        }
+        self.finish_request();
+    }
+
+    pub fn write_list(&mut self, filter: Option<&str>) {
+        TaggedBinary::Int8(COMPONENT_ID_LIST).write_to(&mut self.fifo);
+        if let Some(filter) = filter {
+            TaggedBinary::string(filter).write_to(&mut self.fifo);
+        }
+        self.finish_request();
+    }
 
+    pub fn write_destroy_value(&mut self, value: u32) {
+        TaggedBinary::Int8(COMPONENT_ID_DESTROY_VALUE).write_to(&mut self.fifo);
+        TaggedBinary::Value(value).write_to(&mut self.fifo);
+        self.finish_request();
+    }
+
+    /** Whether any of a response is waiting to be read */
+    pub fn read_ready(&self) -> bool {
+        return self.fifo.read_ready();
+    }
+
+    /** Wait for the start of a response. False if the deadline passed first. */
+    pub fn wait_response(&mut self, deadline: Deadline) -> bool {
+        while !self.fifo.read_ready() {
+            if deadline.expired() {
+                return false;
+            }
+            core::hint::spin_loop();
+        }
+        return true;
     }
-}
\ No newline at end of file
+
+    /**
+     * Read values up to (and consuming) the END that terminates every response. On a decode error the rest of
+     * the response is thrown away, so the next one still starts in the right place.
+     */
+    pub fn read_until_end(&mut self) -> Result<Vec<TaggedBinary<'static>>, DecodeError> {
+        let mut values = Vec::new();
+        loop {
+            let value = match TaggedBinary::read_from(&mut self.fifo) {
+                Ok(value) => value,
+                Err(e) => {
+                    // END bytes can turn up inside values, so there's no finding it reliably; drop everything
+                    self.discard_pending();
+                    return Err(e);
+                }
+            };
+            if value == TaggedBinary::END {
+                return Ok(values);
+            }
+            values.push(value);
+        }
+    }
+
+    /** Throw away everything the host has queued. Responses arrive whole, so this leaves the FIFO between responses. */
+    pub fn discard_pending(&mut self) {
+        while self.fifo.read_ready() {
+            self.fifo.read();
+        }
+    }
+
+    fn finish_request(&mut self) {
+        TaggedBinary::END.write_to(&mut self.fifo);
+        // The host normally answers before this returns, but callers still wait_response in case it doesn't
+        self.fifo.write_ready();
+    }
+}
@@ -0,0 +1,115 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::RwLock;
+use crate::drivers::component_client::{ComponentClient, ComponentError};
+use crate::peripherals::taggedbinary::TaggedBinary;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ComponentInfo {
+    /// Component type, e.g. "filesystem" or "gpu"
+    pub kind: String,
+    /// UUID string
+    pub address: String,
+}
+
+/// What changed in a refresh
+#[derive(Clone, Debug, Default)]
+pub struct RegistryChanges {
+    pub added: Vec<ComponentInfo>,
+    pub removed: Vec<ComponentInfo>,
+}
+
+pub struct ComponentRegistry {
+    components: Vec<ComponentInfo>
+}
+
+static GLOBAL_COMPONENT_REGISTRY: RwLock<Option<ComponentRegistry>> = RwLock::new(None);
+
+impl ComponentRegistry {
+    /** Needs the component client and the heap */
+    pub fn create_global() -> Result<(), ComponentError> {
+        let current = GLOBAL_COMPONENT_REGISTRY.upgradeable_read();
+        if current.is_some() {
+            panic!("Global component registry already initialized")
+        }
+        let mut write = current.upgrade();
+
+        let mut new = ComponentRegistry { components: Vec::new() };
+        new.refresh()?;
+        write.replace(new);
+        return Ok(());
+    }
+
+    pub fn get_global<F, T>(f: F) -> T where F: Fn(&mut ComponentRegistry) -> T {
+        let mut lock = GLOBAL_COMPONENT_REGISTRY.write();
+        let registry = lock.as_mut().unwrap();
+        f(registry)
+    }
+}
+
+impl ComponentRegistry {
+    /** Re-list attached components, e.g. after something was hot-plugged */
+    pub fn refresh(&mut self) -> Result<RegistryChanges, ComponentError> {
+        let listed = ComponentRegistry::list_components()?;
+
+        let mut changes = RegistryChanges::default();
+        for old in &self.components {
+            if !listed.contains(old) {
+                changes.removed.push(old.clone());
+            }
+        }
+        for new in &listed {
+            if !self.components.contains(new) {
+                changes.added.push(new.clone());
+            }
+        }
+        self.components = listed;
+        return Ok(changes);
+    }
+
+    fn list_components() -> Result<Vec<ComponentInfo>, ComponentError> {
+        let values = ComponentClient::get_global(|client| client.list(None))?;
+        if values.len() % 2 != 0 {
+            return Err(ComponentError::Protocol("component list has a type without an address"));
+        }
+
+        let mut components = Vec::with_capacity(values.len() / 2);
+        for pair in values.chunks(2) {
+            match (ComponentRegistry::to_string(&pair[0]), ComponentRegistry::to_string(&pair[1])) {
+                (Some(kind), Some(address)) => components.push(ComponentInfo { kind, address }),
+                _ => return Err(ComponentError::Protocol("component list entry isn't a string"))
+            }
+        }
+        return Ok(components);
+    }
+
+    fn to_string(value: &TaggedBinary) -> Option<String> {
+        value.as_str().map(String::from)
+    }
+
+    pub fn all(&self) -> &[ComponentInfo] {
+        &self.components
+    }
+
+    pub fn find_by_type<'a>(&'a self, kind: &'a str) -> impl Iterator<Item=&'a ComponentInfo> + 'a {
+        self.components.iter().filter(move |c| c.kind == kind)
+    }
+
+    pub fn first_of_type(&self, kind: &str) -> Option<&ComponentInfo> {
+        self.components.iter().find(|c| c.kind == kind)
+    }
+
+    pub fn find_by_address(&self, address: &str) -> Option<&ComponentInfo> {
+        self.components.iter().find(|c| c.address == address)
+    }
+
+    /** Resolve an abbreviated address; None if nothing or more than one component matches */
+    pub fn find_by_address_prefix(&self, prefix: &str) -> Option<&ComponentInfo> {
+        let mut matches = self.components.iter().filter(|c| c.address.starts_with(prefix));
+        let first = matches.next()?;
+        if matches.next().is_some() {
+            return None;
+        }
+        return Some(first);
+    }
+}
@@ -0,0 +1,348 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::RwLock;
+use crate::drivers::gpu_driver::{GPUDriver, GpuError};
+use crate::fs::{File, OpenMode, VfsError, Whence};
+use crate::fs::devfs::{self, Device};
+
+/// Lines kept after they scroll off the top
+pub const SCROLLBACK_LINES: usize = 200;
+
+pub const DEFAULT_FOREGROUND: u32 = 0xFFFFFF;
+pub const DEFAULT_BACKGROUND: u32 = 0x000000;
+
+/// ANSI colors 0-7, then the bright versions
+const ANSI_PALETTE: [u32; 16] = [
+    0x000000, 0xAA0000, 0x00AA00, 0xAA5500, 0x0000AA, 0xAA00AA, 0x00AAAA, 0xAAAAAA,
+    0x555555, 0xFF5555, 0x55FF55, 0xFFFF55, 0x5555FF, 0xFF55FF, 0x55FFFF, 0xFFFFFF,
+];
+
+const MAX_CSI_PARAMS: usize = 8;
+
+enum AnsiState {
+    Normal,
+    /// Saw ESC
+    Escape,
+    /// Inside ESC [ ... ; collecting numeric parameters
+    Csi { params: [u32; MAX_CSI_PARAMS], count: usize, has_digit: bool },
+}
+
+/**
+ * Text console on a gpu. Understands \n, \r, \t, backspace and a subset of ANSI CSI sequences:
+ * SGR colors (m), cursor movement (A B C D H f) and clearing (J K).
+ */
+pub struct Console {
+    gpu: GPUDriver,
+    width: u32,
+    height: u32,
+    /// 0-based
+    cursor_x: u32,
+    cursor_y: u32,
+    foreground: u32,
+    background: u32,
+    /// What we think is on screen, so lines can be saved as they scroll away
+    shadow: Vec<Vec<char>>,
+    scrollback: VecDeque<String>,
+    state: AnsiState,
+}
+
+static GLOBAL_CONSOLE: RwLock<Option<Console>> = RwLock::new(None);
+
+impl Console {
+    pub fn create_global(gpu: GPUDriver) -> Result<(), GpuError> {
+        let current = GLOBAL_CONSOLE.upgradeable_read();
+        if current.is_some() {
+            panic!("Global console already initialized")
+        }
+        let mut write = current.upgrade();
+
+        let console = Console::new(gpu)?;
+        write.replace(console);
+        drop(write);
+
+        let _ = devfs::register("console", Arc::new(ConsoleDevice));
+        return Ok(());
+    }
+
+    pub fn get_global<F, T>(f: F) -> T where F: Fn(&mut Console) -> T {
+        let mut lock = GLOBAL_CONSOLE.write();
+        let console = lock.as_mut().unwrap();
+        f(console)
+    }
+
+    /** Like get_global, but None if there's no console or someone else is using it */
+    pub fn try_get_global<F, T>(f: F) -> Option<T> where F: Fn(&mut Console) -> T {
+        let mut lock = GLOBAL_CONSOLE.try_write()?;
+        let console = lock.as_mut()?;
+        Some(f(console))
+    }
+}
+
+impl Console {
+    pub fn new(mut gpu: GPUDriver) -> Result<Console, GpuError> {
+        let (width, height) = gpu.get_resolution()?;
+        let mut console = Console {
+            gpu,
+            width,
+            height,
+            cursor_x: 0,
+            cursor_y: 0,
+            foreground: DEFAULT_FOREGROUND,
+            background: DEFAULT_BACKGROUND,
+            shadow: vec![vec![' '; width as usize]; height as usize],
+            scrollback: VecDeque::new(),
+            state: AnsiState::Normal,
+        };
+        console.clear()?;
+        return Ok(console);
+    }
+
+    pub fn clear(&mut self) -> Result<(), GpuError> {
+        self.clear_region(0, 0, self.width, self.height)?;
+        self.cursor_x = 0;
+        self.cursor_y = 0;
+        return Ok(());
+    }
+
+    pub fn scrollback(&self) -> impl Iterator<Item=&String> {
+        self.scrollback.iter()
+    }
+
+    /** Redraw the screen as it looked `lines` lines ago. 0 shows the live screen. */
+    pub fn view_history(&mut self, lines: usize) -> Result<(), GpuError> {
+        let lines = lines.min(self.scrollback.len());
+        let history_start = self.scrollback.len() - lines;
+
+        self.gpu.set_foreground(DEFAULT_FOREGROUND)?;
+        self.gpu.set_background(DEFAULT_BACKGROUND)?;
+        for row in 0..self.height as usize {
+            let text: String = if row < lines {
+                let mut line = self.scrollback[history_start + row].clone();
+                let pad = (self.width as usize).saturating_sub(line.chars().count());
+                line.extend(core::iter::repeat_n(' ', pad));
+                line
+            } else {
+                self.shadow[row - lines].iter().collect()
+            };
+            self.gpu.set(1, row as u32 + 1, &text)?;
+        }
+        self.gpu.flush()?;
+        // Colors were only tracked by the shadow buffer; the live view is drawn in defaults
+        self.gpu.set_foreground(self.foreground)?;
+        self.gpu.set_background(self.background)?;
+        return Ok(());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), GpuError> {
+        // Decode UTF-8 leniently; console output shouldn't fail over a bad byte
+        for c in String::from_utf8_lossy(bytes).chars() {
+            self.put_char(c)?;
+        }
+        return self.gpu.flush();
+    }
+
+    fn put_char(&mut self, c: char) -> Result<(), GpuError> {
+        match &mut self.state {
+            AnsiState::Normal => {}
+            AnsiState::Escape => {
+                self.state = if c == '[' {
+                    AnsiState::Csi { params: [0; MAX_CSI_PARAMS], count: 0, has_digit: false }
+                } else {
+                    // Unsupported escape; drop it
+                    AnsiState::Normal
+                };
+                return Ok(());
+            }
+            AnsiState::Csi { params, count, has_digit } => {
+                match c {
+                    '0'..='9' => {
+                        if *count < MAX_CSI_PARAMS {
+                            // Anything can be written to the console, so a huge number just sticks at the max
+                            params[*count] = params[*count].saturating_mul(10).saturating_add(c.to_digit(10).unwrap());
+                        }
+                        *has_digit = true;
+                    }
+                    ';' => {
+                        *count = (*count + 1).min(MAX_CSI_PARAMS);
+                        *has_digit = false;
+                    }
+                    _ => {
+                        let used = if *has_digit || *count > 0 { (*count + 1).min(MAX_CSI_PARAMS) } else { 0 };
+                        let params = *params;
+                        self.state = AnsiState::Normal;
+                        return self.handle_csi(c, &params[..used]);
+                    }
+                }
+                return Ok(());
+            }
+        }
+
+        match c {
+            '\x1b' => self.state = AnsiState::Escape,
+            '\n' => self.newline()?,
+            '\r' => self.cursor_x = 0,
+            '\t' => {
+                let next = (self.cursor_x / 8 + 1) * 8;
+                while self.cursor_x < next.min(self.width) {
+                    self.put_printable(' ')?;
+                }
+            }
+            '\x08' => self.cursor_x = self.cursor_x.saturating_sub(1),
+            c if c.is_control() => {}
+            c => self.put_printable(c)?
+        }
+        return Ok(());
+    }
+
+    fn put_printable(&mut self, c: char) -> Result<(), GpuError> {
+        if self.cursor_x >= self.width {
+            self.newline()?;
+        }
+        self.gpu.set_foreground(self.foreground)?;
+        self.gpu.set_background(self.background)?;
+        let mut buf = [0u8; 4];
+        self.gpu.set(self.cursor_x + 1, self.cursor_y + 1, c.encode_utf8(&mut buf))?;
+        self.shadow[self.cursor_y as usize][self.cursor_x as usize] = c;
+        self.cursor_x += 1;
+        return Ok(());
+    }
+
+    fn newline(&mut self) -> Result<(), GpuError> {
+        self.cursor_x = 0;
+        if self.cursor_y + 1 < self.height {
+            self.cursor_y += 1;
+            return Ok(());
+        }
+        return self.scroll();
+    }
+
+    /** Move everything up a line, saving the top one */
+    fn scroll(&mut self) -> Result<(), GpuError> {
+        let top = self.shadow.remove(0);
+        let line: String = top.into_iter().collect();
+        if self.scrollback.len() >= SCROLLBACK_LINES {
+            self.scrollback.pop_front();
+        }
+        self.scrollback.push_back(String::from(line.trim_end()));
+        self.shadow.push(vec![' '; self.width as usize]);
+
+        if self.height > 1 {
+            self.gpu.copy(1, 2, self.width, self.height - 1, 0, -1)?;
+        }
+        self.gpu.set_background(self.background)?;
+        self.gpu.fill(1, self.height, self.width, 1, ' ')?;
+        return Ok(());
+    }
+
+    fn clear_region(&mut self, x: u32, y: u32, width: u32, height: u32) -> Result<(), GpuError> {
+        if width == 0 || height == 0 {
+            return Ok(());
+        }
+        self.gpu.set_background(self.background)?;
+        self.gpu.fill(x + 1, y + 1, width, height, ' ')?;
+        for row in y..(y + height) {
+            for col in x..(x + width) {
+                self.shadow[row as usize][col as usize] = ' ';
+            }
+        }
+        return Ok(());
+    }
+
+    fn handle_csi(&mut self, command: char, params: &[u32]) -> Result<(), GpuError> {
+        // Missing/zero counts mean 1 for movement
+        let count = params.first().copied().unwrap_or(0).max(1);
+        match command {
+            'm' => self.handle_sgr(params),
+            'A' => self.cursor_y = self.cursor_y.saturating_sub(count),
+            'B' => self.cursor_y = self.cursor_y.saturating_add(count).min(self.height - 1),
+            'C' => self.cursor_x = self.cursor_x.saturating_add(count).min(self.width - 1),
+            'D' => self.cursor_x = self.cursor_x.saturating_sub(count),
+            'H' | 'f' => {
+                let row = params.first().copied().unwrap_or(1).max(1);
+                let col = params.get(1).copied().unwrap_or(1).max(1);
+                self.cursor_y = (row - 1).min(self.height - 1);
+                self.cursor_x = (col - 1).min(self.width - 1);
+            }
+            'J' => match params.first().copied().unwrap_or(0) {
+                0 => {
+                    self.clear_region(self.cursor_x, self.cursor_y, self.width - self.cursor_x, 1)?;
+                    self.clear_region(0, self.cursor_y + 1, self.width, self.height - self.cursor_y - 1)?;
+                }
+                1 => {
+                    self.clear_region(0, 0, self.width, self.cursor_y)?;
+                    self.clear_region(0, self.cursor_y, (self.cursor_x + 1).min(self.width), 1)?;
+                }
+                _ => self.clear_region(0, 0, self.width, self.height)?
+            },
+            'K' => match params.first().copied().unwrap_or(0) {
+                0 => self.clear_region(self.cursor_x, self.cursor_y, self.width - self.cursor_x, 1)?,
+                1 => self.clear_region(0, self.cursor_y, (self.cursor_x + 1).min(self.width), 1)?,
+                _ => self.clear_region(0, self.cursor_y, self.width, 1)?
+            },
+            // Anything else is ignored
+            _ => {}
+        }
+        return Ok(());
+    }
+
+    fn handle_sgr(&mut self, params: &[u32]) {
+        if params.is_empty() {
+            self.foreground = DEFAULT_FOREGROUND;
+            self.background = DEFAULT_BACKGROUND;
+            return;
+        }
+        for param in params {
+            match *param {
+                0 => {
+                    self.foreground = DEFAULT_FOREGROUND;
+                    self.background = DEFAULT_BACKGROUND;
+                }
+                30..=37 => self.foreground = ANSI_PALETTE[(*param - 30) as usize],
+                39 => self.foreground = DEFAULT_FOREGROUND,
+                40..=47 => self.background = ANSI_PALETTE[(*param - 40) as usize],
+                49 => self.background = DEFAULT_BACKGROUND,
+                90..=97 => self.foreground = ANSI_PALETTE[(*param - 90 + 8) as usize],
+                100..=107 => self.background = ANSI_PALETTE[(*param - 100 + 8) as usize],
+                // Bold, underline etc. aren't something the gpu can do
+                _ => {}
+            }
+        }
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+/// /dev/console
+struct ConsoleDevice;
+
+impl Device for ConsoleDevice {
+    fn open(&self, _mode: OpenMode) -> Result<Box<dyn File>, VfsError> {
+        return Ok(Box::new(ConsoleDevice));
+    }
+}
+
+impl File for ConsoleDevice {
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize, VfsError> {
+        // No keyboard yet
+        return Ok(0);
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, VfsError> {
+        match Console::try_get_global(|console| console.write_bytes(buf).is_ok()) {
+            Some(true) => Ok(buf.len()),
+            _ => Err(VfsError::NotSupported)
+        }
+    }
+
+    fn seek(&mut self, _whence: Whence, _offset: i64) -> Result<u64, VfsError> {
+        return Ok(0);
+    }
+}
@@ -0,0 +1,250 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use crate::drivers::component_client::{ComponentClient, ComponentError};
+use crate::drivers::remote_value::RemoteValue;
+use crate::peripherals::taggedbinary::TaggedBinary;
+
+/// Largest read the host will satisfy in one call
+pub const READ_CHUNK_SIZE: usize = 2048;
+
+#[derive(Clone, Debug)]
+pub enum FsError {
+    Component(ComponentError),
+    /// The call returned nil plus a reason (e.g. "file not found")
+    Failed(String),
+    /// The call succeeded, but returned something we didn't expect
+    BadResponse(&'static str),
+}
+
+impl From<ComponentError> for FsError {
+    fn from(e: ComponentError) -> Self {
+        FsError::Component(e)
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsError::Component(e) => write!(f, "{}", e),
+            FsError::Failed(reason) => write!(f, "filesystem error: {}", reason),
+            FsError::BadResponse(msg) => write!(f, "unexpected filesystem response: {}", msg),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OpenMode {
+    Read,
+    /// Truncates
+    Write,
+    Append,
+}
+
+impl OpenMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            OpenMode::Read => "rb",
+            OpenMode::Write => "wb",
+            OpenMode::Append => "ab",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Whence {
+    Set,
+    Cur,
+    End,
+}
+
+impl Whence {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Whence::Set => "set",
+            Whence::Cur => "cur",
+            Whence::End => "end",
+        }
+    }
+}
+
+/** An open file on a filesystem component. Dropping it without close() still frees the host handle. */
+#[derive(Clone, Debug)]
+pub struct FileHandle {
+    value: RemoteValue
+}
+
+/** A filesystem component */
+#[derive(Clone, Debug)]
+pub struct Filesystem {
+    address: String
+}
+
+impl Filesystem {
+    pub fn new(address: &str) -> Filesystem {
+        Filesystem {
+            address: String::from(address)
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn open(&self, path: &str, mode: OpenMode) -> Result<FileHandle, FsError> {
+        let result = self.call("open", &[TaggedBinary::string(path), TaggedBinary::string(mode.as_str())])?;
+        match result.into_iter().next() {
+            Some(TaggedBinary::Handle(value)) => Ok(FileHandle { value }),
+            _ => Err(FsError::BadResponse("open didn't return a handle"))
+        }
+    }
+
+    pub fn close(&self, handle: FileHandle) -> Result<(), FsError> {
+        self.call("close", &[TaggedBinary::Handle(handle.value)])?;
+        return Ok(());
+    }
+
+    /** Read up to `count` bytes (capped at READ_CHUNK_SIZE by the host). An empty result means EOF. */
+    pub fn read(&self, handle: &FileHandle, count: usize) -> Result<Vec<u8>, FsError> {
+        let result = self.call("read", &[TaggedBinary::Handle(handle.value.clone()), TaggedBinary::Int32(count as u32)])?;
+        match result.into_iter().next() {
+            Some(TaggedBinary::Bytes(data)) => Ok(data.into_owned()),
+            // nil is EOF
+            Some(TaggedBinary::NULL) | None => Ok(Vec::new()),
+            _ => Err(FsError::BadResponse("read didn't return bytes"))
+        }
+    }
+
+    /** Fill as much of `dest` as the file allows, returning how much was read */
+    pub fn read_into(&self, handle: &FileHandle, dest: &mut [u8]) -> Result<usize, FsError> {
+        let mut total = 0;
+        while total < dest.len() {
+            let want = (dest.len() - total).min(READ_CHUNK_SIZE);
+            let data = self.read(handle, want)?;
+            if data.is_empty() {
+                break;
+            }
+            let got = data.len().min(dest.len() - total);
+            dest[total..total + got].copy_from_slice(&data[..got]);
+            total += got;
+        }
+        return Ok(total);
+    }
+
+    pub fn write(&self, handle: &FileHandle, data: &[u8]) -> Result<(), FsError> {
+        let result = self.call("write", &[TaggedBinary::Handle(handle.value.clone()), TaggedBinary::bytes(data)])?;
+        return Filesystem::expect_true(&result, "write");
+    }
+
+    /** Returns the new position */
+    pub fn seek(&self, handle: &FileHandle, whence: Whence, offset: i32) -> Result<u64, FsError> {
+        let result = self.call("seek", &[
+            TaggedBinary::Handle(handle.value.clone()),
+            TaggedBinary::string(whence.as_str()),
+            TaggedBinary::Int32(offset as u32)
+        ])?;
+        return Filesystem::expect_number(&result, "seek");
+    }
+
+    pub fn list(&self, path: &str) -> Result<Vec<String>, FsError> {
+        let result = self.call("list", &[TaggedBinary::string(path)])?;
+        let entries = match result.first().and_then(|r| r.as_object()) {
+            Some(entries) => entries,
+            None => return Err(FsError::BadResponse("list didn't return a table"))
+        };
+
+        // Array-style table; keep the host's order
+        let mut names: Vec<(u64, String)> = Vec::with_capacity(entries.len());
+        for (key, value) in entries {
+            match (key.as_u64(), value.as_str()) {
+                (Some(index), Some(name)) => names.push((index, String::from(name))),
+                _ => return Err(FsError::BadResponse("list entry isn't a string"))
+            }
+        }
+        names.sort_by_key(|(index, _)| *index);
+        return Ok(names.into_iter().map(|(_, name)| name).collect());
+    }
+
+    pub fn exists(&self, path: &str) -> Result<bool, FsError> {
+        let result = self.call("exists", &[TaggedBinary::string(path)])?;
+        return Filesystem::expect_bool(&result, "exists");
+    }
+
+    pub fn is_directory(&self, path: &str) -> Result<bool, FsError> {
+        let result = self.call("isDirectory", &[TaggedBinary::string(path)])?;
+        return Filesystem::expect_bool(&result, "isDirectory");
+    }
+
+    pub fn size(&self, path: &str) -> Result<u64, FsError> {
+        let result = self.call("size", &[TaggedBinary::string(path)])?;
+        return Filesystem::expect_number(&result, "size");
+    }
+
+    pub fn make_directory(&self, path: &str) -> Result<(), FsError> {
+        let result = self.call("makeDirectory", &[TaggedBinary::string(path)])?;
+        return Filesystem::expect_true(&result, "makeDirectory");
+    }
+
+    pub fn remove(&self, path: &str) -> Result<(), FsError> {
+        let result = self.call("remove", &[TaggedBinary::string(path)])?;
+        return Filesystem::expect_true(&result, "remove");
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), FsError> {
+        let result = self.call("rename", &[TaggedBinary::string(from), TaggedBinary::string(to)])?;
+        return Filesystem::expect_true(&result, "rename");
+    }
+
+    pub fn space_used(&self) -> Result<u64, FsError> {
+        let result = self.call("spaceUsed", &[])?;
+        return Filesystem::expect_number(&result, "spaceUsed");
+    }
+
+    pub fn space_total(&self) -> Result<u64, FsError> {
+        let result = self.call("spaceTotal", &[])?;
+        return Filesystem::expect_number(&result, "spaceTotal");
+    }
+
+    pub fn get_label(&self) -> Result<Option<String>, FsError> {
+        let result = self.call("getLabel", &[])?;
+        match result.first() {
+            Some(TaggedBinary::NULL) | None => Ok(None),
+            Some(label) => match label.as_str() {
+                Some(label) => Ok(Some(String::from(label))),
+                None => Err(FsError::BadResponse("getLabel didn't return a string"))
+            }
+        }
+    }
+
+    /** Invoke, turning the host's "nil, reason" convention into an error */
+    fn call(&self, method: &str, args: &[TaggedBinary]) -> Result<Vec<TaggedBinary<'static>>, FsError> {
+        let result = ComponentClient::get_global(|client| client.invoke(&self.address, method, args))?;
+        if let (Some(TaggedBinary::NULL), Some(reason)) = (result.first(), result.get(1)) {
+            if let Some(reason) = reason.as_str() {
+                return Err(FsError::Failed(String::from(reason)));
+            }
+        }
+        return Ok(result);
+    }
+
+    fn expect_bool(result: &[TaggedBinary], method: &'static str) -> Result<bool, FsError> {
+        match result.first().and_then(|r| r.as_u64()) {
+            Some(v) => Ok(v != 0),
+            None => Err(FsError::BadResponse(method))
+        }
+    }
+
+    fn expect_true(result: &[TaggedBinary], method: &'static str) -> Result<(), FsError> {
+        if Filesystem::expect_bool(result, method)? {
+            return Ok(());
+        }
+        return Err(FsError::Failed(String::from(method) + " failed"));
+    }
+
+    fn expect_number(result: &[TaggedBinary], method: &'static str) -> Result<u64, FsError> {
+        match result.first().and_then(|r| r.as_u64()) {
+            Some(v) => Ok(v),
+            None => Err(FsError::BadResponse(method))
+        }
+    }
+}
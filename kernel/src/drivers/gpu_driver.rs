@@ -1,7 +1,214 @@
-// struct GPUDriver {
-//
-// }
-//
-// impl GPUDriver {
-//
-// }
\ No newline at end of file
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use crate::drivers::component_client::{ComponentClient, ComponentError};
+use crate::drivers::component_registry::ComponentRegistry;
+use crate::peripherals::taggedbinary::TaggedBinary;
+
+#[derive(Clone, Debug)]
+pub enum GpuError {
+    Component(ComponentError),
+    /// No gpu or screen component to bind to
+    NoDevice,
+    /// The gpu refused the screen; this is its reason
+    BindFailed(String),
+    /// The call succeeded, but returned something we didn't expect
+    BadResponse(&'static str),
+}
+
+impl From<ComponentError> for GpuError {
+    fn from(e: ComponentError) -> Self {
+        GpuError::Component(e)
+    }
+}
+
+impl fmt::Display for GpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GpuError::Component(e) => write!(f, "{}", e),
+            GpuError::NoDevice => write!(f, "no gpu or screen available"),
+            GpuError::BindFailed(reason) => write!(f, "unable to bind screen: {}", reason),
+            GpuError::BadResponse(msg) => write!(f, "unexpected gpu response: {}", msg),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Cell {
+    pub character: char,
+    pub foreground: u32,
+    pub background: u32,
+}
+
+/// Text waiting to go out in one `set` call
+struct PendingSet {
+    x: u32,
+    y: u32,
+    text: String,
+}
+
+/**
+ * A gpu component bound to a screen. Coordinates are 1-based, like the host's.
+ *
+ * Consecutive set() calls on the same row are merged, and color changes that don't change anything
+ * are skipped. Anything that reads the screen or draws some other way flushes first.
+ */
+pub struct GPUDriver {
+    gpu: String,
+    screen: String,
+    foreground: Option<u32>,
+    background: Option<u32>,
+    pending: Option<PendingSet>,
+}
+
+impl GPUDriver {
+    pub fn bind(gpu: &str, screen: &str) -> Result<GPUDriver, GpuError> {
+        let driver = GPUDriver {
+            gpu: String::from(gpu),
+            screen: String::from(screen),
+            foreground: None,
+            background: None,
+            pending: None,
+        };
+        let result = driver.call("bind", &[TaggedBinary::string(screen)])?;
+        if let Some(TaggedBinary::NULL) = result.first() {
+            let reason = result.get(1).and_then(|r| r.as_str()).unwrap_or("unknown");
+            return Err(GpuError::BindFailed(String::from(reason)));
+        }
+        return Ok(driver);
+    }
+
+    /** Bind the first gpu to the first screen the registry knows about */
+    pub fn bind_first() -> Result<GPUDriver, GpuError> {
+        let found = ComponentRegistry::get_global(|registry| {
+            let gpu = registry.first_of_type("gpu")?.address.clone();
+            let screen = registry.first_of_type("screen")?.address.clone();
+            Some((gpu, screen))
+        });
+        match found {
+            Some((gpu, screen)) => GPUDriver::bind(&gpu, &screen),
+            None => Err(GpuError::NoDevice)
+        }
+    }
+
+    pub fn gpu_address(&self) -> &str {
+        &self.gpu
+    }
+
+    pub fn screen_address(&self) -> &str {
+        &self.screen
+    }
+
+    /** Write text starting at (x, y). May be held back and merged with the next set(). */
+    pub fn set(&mut self, x: u32, y: u32, text: &str) -> Result<(), GpuError> {
+        if let Some(pending) = &mut self.pending {
+            if pending.y == y && pending.x + pending.text.chars().count() as u32 == x {
+                pending.text.push_str(text);
+                return Ok(());
+            }
+        }
+        self.flush()?;
+        self.pending = Some(PendingSet { x, y, text: String::from(text) });
+        return Ok(());
+    }
+
+    pub fn set_vertical(&mut self, x: u32, y: u32, text: &str) -> Result<(), GpuError> {
+        self.flush()?;
+        self.call("set", &[TaggedBinary::Int32(x), TaggedBinary::Int32(y), TaggedBinary::string(text), TaggedBinary::Int8(1)])?;
+        return Ok(());
+    }
+
+    pub fn get(&mut self, x: u32, y: u32) -> Result<Cell, GpuError> {
+        self.flush()?;
+        let result = self.call("get", &[TaggedBinary::Int32(x), TaggedBinary::Int32(y)])?;
+        let character = result.first()
+            .and_then(|c| c.as_str())
+            .and_then(|c| c.chars().next())
+            .ok_or(GpuError::BadResponse("get didn't return a character"))?;
+        let foreground = GPUDriver::number(&result, 1, "get")?;
+        let background = GPUDriver::number(&result, 2, "get")?;
+        return Ok(Cell { character, foreground, background });
+    }
+
+    pub fn fill(&mut self, x: u32, y: u32, width: u32, height: u32, character: char) -> Result<(), GpuError> {
+        self.flush()?;
+        let mut buf = [0u8; 4];
+        let character = character.encode_utf8(&mut buf);
+        self.call("fill", &[
+            TaggedBinary::Int32(x), TaggedBinary::Int32(y),
+            TaggedBinary::Int32(width), TaggedBinary::Int32(height),
+            TaggedBinary::string(character)
+        ])?;
+        return Ok(());
+    }
+
+    /** Copy a region by (dx, dy) */
+    pub fn copy(&mut self, x: u32, y: u32, width: u32, height: u32, dx: i32, dy: i32) -> Result<(), GpuError> {
+        self.flush()?;
+        self.call("copy", &[
+            TaggedBinary::Int32(x), TaggedBinary::Int32(y),
+            TaggedBinary::Int32(width), TaggedBinary::Int32(height),
+            TaggedBinary::Int32(dx as u32), TaggedBinary::Int32(dy as u32)
+        ])?;
+        return Ok(());
+    }
+
+    /** 0xRRGGBB */
+    pub fn set_foreground(&mut self, color: u32) -> Result<(), GpuError> {
+        if self.foreground == Some(color) {
+            return Ok(());
+        }
+        // Pending text was meant for the old color
+        self.flush()?;
+        self.call("setForeground", &[TaggedBinary::Int32(color)])?;
+        self.foreground = Some(color);
+        return Ok(());
+    }
+
+    /** 0xRRGGBB */
+    pub fn set_background(&mut self, color: u32) -> Result<(), GpuError> {
+        if self.background == Some(color) {
+            return Ok(());
+        }
+        self.flush()?;
+        self.call("setBackground", &[TaggedBinary::Int32(color)])?;
+        self.background = Some(color);
+        return Ok(());
+    }
+
+    pub fn get_resolution(&mut self) -> Result<(u32, u32), GpuError> {
+        self.flush()?;
+        let result = self.call("getResolution", &[])?;
+        return Ok((GPUDriver::number(&result, 0, "getResolution")?, GPUDriver::number(&result, 1, "getResolution")?));
+    }
+
+    pub fn set_resolution(&mut self, width: u32, height: u32) -> Result<(), GpuError> {
+        self.flush()?;
+        self.call("setResolution", &[TaggedBinary::Int32(width), TaggedBinary::Int32(height)])?;
+        return Ok(());
+    }
+
+    pub fn max_resolution(&mut self) -> Result<(u32, u32), GpuError> {
+        let result = self.call("maxResolution", &[])?;
+        return Ok((GPUDriver::number(&result, 0, "maxResolution")?, GPUDriver::number(&result, 1, "maxResolution")?));
+    }
+
+    /** Send any text held back by set() */
+    pub fn flush(&mut self) -> Result<(), GpuError> {
+        if let Some(pending) = self.pending.take() {
+            self.call("set", &[TaggedBinary::Int32(pending.x), TaggedBinary::Int32(pending.y), TaggedBinary::string(&pending.text)])?;
+        }
+        return Ok(());
+    }
+
+    fn call(&self, method: &str, args: &[TaggedBinary]) -> Result<Vec<TaggedBinary<'static>>, GpuError> {
+        return Ok(ComponentClient::get_global(|client| client.invoke(&self.gpu, method, args))?);
+    }
+
+    fn number(result: &[TaggedBinary], index: usize, method: &'static str) -> Result<u32, GpuError> {
+        match result.get(index).and_then(|r| r.as_u64()) {
+            Some(v) => Ok(v as u32),
+            None => Err(GpuError::BadResponse(method))
+        }
+    }
+}
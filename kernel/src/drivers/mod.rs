@@ -1,2 +1,7 @@
+pub mod component_client;
 pub mod component_fifo;
-pub mod gpu_driver;
\ No newline at end of file
+pub mod component_registry;
+pub mod console;
+pub mod filesystem;
+pub mod gpu_driver;
+pub mod remote_value;
\ No newline at end of file
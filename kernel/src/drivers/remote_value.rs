@@ -0,0 +1,57 @@
+use alloc::sync::Arc;
+use core::fmt;
+use core::mem;
+use crate::drivers::component_client::ComponentClient;
+
+/** A host-side value (file handle etc). The host is told to destroy it when the last clone drops. */
+#[derive(Clone)]
+pub struct RemoteValue {
+    inner: Arc<RemoteValueInner>
+}
+
+struct RemoteValueInner {
+    id: u32
+}
+
+impl RemoteValue {
+    /** Take ownership of a value id the host handed us */
+    pub fn adopt(id: u32) -> RemoteValue {
+        RemoteValue {
+            inner: Arc::new(RemoteValueInner { id })
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.inner.id
+    }
+
+    pub fn ref_count(&self) -> usize {
+        Arc::strong_count(&self.inner)
+    }
+
+    /** Give up ownership without destroying the value. None if other clones are still alive. */
+    pub fn into_raw(self) -> Option<u32> {
+        let inner = Arc::try_unwrap(self.inner).ok()?;
+        let id = inner.id;
+        mem::forget(inner);
+        return Some(id);
+    }
+}
+
+impl Drop for RemoteValueInner {
+    fn drop(&mut self) {
+        ComponentClient::release_value(self.id);
+    }
+}
+
+impl PartialEq for RemoteValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.id() == other.id()
+    }
+}
+
+impl fmt::Debug for RemoteValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RemoteValue({}, refs: {})", self.id(), self.ref_count())
+    }
+}
@@ -0,0 +1,98 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use crate::drivers::filesystem::{FileHandle, Filesystem, READ_CHUNK_SIZE};
+use crate::fs::{File, FileSystem, OpenMode, VfsError, Whence};
+
+/** A filesystem component mounted into the VFS */
+pub struct ComponentFs {
+    fs: Filesystem
+}
+
+impl ComponentFs {
+    pub fn new(address: &str) -> ComponentFs {
+        ComponentFs {
+            fs: Filesystem::new(address)
+        }
+    }
+}
+
+pub struct ComponentFile {
+    fs: Filesystem,
+    handle: Option<FileHandle>,
+}
+
+impl File for ComponentFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let handle = self.handle.as_ref().ok_or(VfsError::BadDescriptor)?;
+        let data = self.fs.read(handle, buf.len().min(READ_CHUNK_SIZE))?;
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        return Ok(len);
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, VfsError> {
+        let handle = self.handle.as_ref().ok_or(VfsError::BadDescriptor)?;
+        self.fs.write(handle, buf)?;
+        return Ok(buf.len());
+    }
+
+    fn seek(&mut self, whence: Whence, offset: i64) -> Result<u64, VfsError> {
+        let handle = self.handle.as_ref().ok_or(VfsError::BadDescriptor)?;
+        // The component only takes 32 bit offsets
+        let offset = i32::try_from(offset).map_err(|_| VfsError::InvalidArgument)?;
+        return Ok(self.fs.seek(handle, whence, offset)?);
+    }
+}
+
+impl Drop for ComponentFile {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            // Nothing to report the error to; the handle gets destroyed either way
+            let _ = self.fs.close(handle);
+        }
+    }
+}
+
+impl FileSystem for ComponentFs {
+    fn describe(&self) -> String {
+        format!("filesystem {}", self.fs.address())
+    }
+
+    fn open(&self, path: &str, mode: OpenMode) -> Result<Box<dyn File>, VfsError> {
+        let handle = self.fs.open(path, mode)?;
+        return Ok(Box::new(ComponentFile {
+            fs: self.fs.clone(),
+            handle: Some(handle),
+        }));
+    }
+
+    fn list(&self, path: &str) -> Result<Vec<String>, VfsError> {
+        return Ok(self.fs.list(path)?);
+    }
+
+    fn exists(&self, path: &str) -> Result<bool, VfsError> {
+        return Ok(self.fs.exists(path)?);
+    }
+
+    fn is_directory(&self, path: &str) -> Result<bool, VfsError> {
+        return Ok(self.fs.is_directory(path)?);
+    }
+
+    fn size(&self, path: &str) -> Result<u64, VfsError> {
+        return Ok(self.fs.size(path)?);
+    }
+
+    fn make_directory(&self, path: &str) -> Result<(), VfsError> {
+        return Ok(self.fs.make_directory(path)?);
+    }
+
+    fn remove(&self, path: &str) -> Result<(), VfsError> {
+        return Ok(self.fs.remove(path)?);
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), VfsError> {
+        return Ok(self.fs.rename(from, to)?);
+    }
+}
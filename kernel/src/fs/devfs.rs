@@ -0,0 +1,138 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::RwLock;
+use crate::fs::{File, FileSystem, OpenMode, VfsError, Whence};
+use crate::fs::path;
+use crate::peripherals::basic_fifo::BasicFIFO;
+use crate::peripherals::stream::OutStream;
+
+/** A device node. Drivers register these to show up under /dev. */
+pub trait Device: Send + Sync {
+    fn open(&self, mode: OpenMode) -> Result<Box<dyn File>, VfsError>;
+}
+
+/// Shared by every DevFs mount
+static DEVICES: RwLock<BTreeMap<String, Arc<dyn Device>>> = RwLock::new(BTreeMap::new());
+
+pub fn register(name: &str, device: Arc<dyn Device>) -> Result<(), VfsError> {
+    let mut devices = DEVICES.write();
+    if devices.contains_key(name) {
+        return Err(VfsError::AlreadyExists);
+    }
+    devices.insert(String::from(name), device);
+    return Ok(());
+}
+
+/** Flat filesystem of device nodes */
+pub struct DevFs;
+
+impl DevFs {
+    /** Also registers the built-in nodes, if they aren't already */
+    pub fn new() -> DevFs {
+        let _ = register("null", Arc::new(SimpleDevice::Null));
+        let _ = register("zero", Arc::new(SimpleDevice::Zero));
+        let _ = register("kmsg", Arc::new(SimpleDevice::Kmsg));
+        DevFs
+    }
+
+    fn lookup(path: &str) -> Option<Arc<dyn Device>> {
+        let path = path::normalize(path);
+        if path::parent(&path) != "/" {
+            return None;
+        }
+        return DEVICES.read().get(path::file_name(&path)).cloned();
+    }
+}
+
+impl Default for DevFs {
+    fn default() -> Self {
+        DevFs::new()
+    }
+}
+
+#[derive(Copy, Clone)]
+enum SimpleDevice {
+    /// Discards writes, always at EOF
+    Null,
+    /// Discards writes, reads zeros forever
+    Zero,
+    /// Writes go to the print FIFO
+    Kmsg,
+}
+
+impl Device for SimpleDevice {
+    fn open(&self, _mode: OpenMode) -> Result<Box<dyn File>, VfsError> {
+        return Ok(Box::new(*self));
+    }
+}
+
+impl File for SimpleDevice {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, VfsError> {
+        match self {
+            SimpleDevice::Zero => {
+                buf.iter_mut().for_each(|b| *b = 0);
+                Ok(buf.len())
+            }
+            SimpleDevice::Null | SimpleDevice::Kmsg => Ok(0)
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, VfsError> {
+        if let SimpleDevice::Kmsg = self {
+            BasicFIFO::print_fifo().write_bytes(buf);
+        }
+        return Ok(buf.len());
+    }
+
+    fn seek(&mut self, _whence: Whence, _offset: i64) -> Result<u64, VfsError> {
+        return Ok(0);
+    }
+}
+
+impl FileSystem for DevFs {
+    fn describe(&self) -> String {
+        String::from("devfs")
+    }
+
+    fn open(&self, path: &str, mode: OpenMode) -> Result<Box<dyn File>, VfsError> {
+        match DevFs::lookup(path) {
+            Some(device) => device.open(mode),
+            None if path::normalize(path) == "/" => Err(VfsError::IsADirectory),
+            None => Err(VfsError::NotFound)
+        }
+    }
+
+    fn list(&self, path: &str) -> Result<Vec<String>, VfsError> {
+        if path::normalize(path) != "/" {
+            return Err(if DevFs::lookup(path).is_some() { VfsError::NotADirectory } else { VfsError::NotFound });
+        }
+        return Ok(DEVICES.read().keys().cloned().collect());
+    }
+
+    fn exists(&self, path: &str) -> Result<bool, VfsError> {
+        return Ok(path::normalize(path) == "/" || DevFs::lookup(path).is_some());
+    }
+
+    fn is_directory(&self, path: &str) -> Result<bool, VfsError> {
+        return Ok(path::normalize(path) == "/");
+    }
+
+    fn size(&self, path: &str) -> Result<u64, VfsError> {
+        return if self.exists(path)? { Ok(0) } else { Err(VfsError::NotFound) };
+    }
+
+    fn make_directory(&self, _path: &str) -> Result<(), VfsError> {
+        return Err(VfsError::NotSupported);
+    }
+
+    fn remove(&self, _path: &str) -> Result<(), VfsError> {
+        return Err(VfsError::NotSupported);
+    }
+
+    fn rename(&self, _from: &str, _to: &str) -> Result<(), VfsError> {
+        return Err(VfsError::NotSupported);
+    }
+}
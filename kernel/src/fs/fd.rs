@@ -0,0 +1,82 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::fs::{File, OpenMode, VfsError};
+
+pub const MAX_FILE_DESCRIPTORS: usize = 64;
+
+/// Shared between descriptors after dup() or when a table is cloned
+pub type FileRef = Arc<Mutex<Box<dyn File>>>;
+
+/** Per-process open files, indexed by descriptor */
+#[derive(Clone, Default)]
+pub struct FileDescriptorTable {
+    files: Vec<Option<FileRef>>
+}
+
+impl FileDescriptorTable {
+    pub fn new() -> FileDescriptorTable {
+        FileDescriptorTable {
+            files: Vec::new()
+        }
+    }
+
+    pub fn open(&mut self, path: &str, mode: OpenMode) -> Result<usize, VfsError> {
+        let file = crate::fs::open(path, mode)?;
+        return self.insert(Arc::new(Mutex::new(file)));
+    }
+
+    /** Put a file in the lowest free slot */
+    pub fn insert(&mut self, file: FileRef) -> Result<usize, VfsError> {
+        if let Some(fd) = self.files.iter().position(|f| f.is_none()) {
+            self.files[fd] = Some(file);
+            return Ok(fd);
+        }
+        if self.files.len() >= MAX_FILE_DESCRIPTORS {
+            return Err(VfsError::TooManyOpenFiles);
+        }
+        self.files.push(Some(file));
+        return Ok(self.files.len() - 1);
+    }
+
+    pub fn get(&self, fd: usize) -> Result<FileRef, VfsError> {
+        match self.files.get(fd) {
+            Some(Some(file)) => Ok(file.clone()),
+            _ => Err(VfsError::BadDescriptor)
+        }
+    }
+
+    /** The file itself closes once no descriptor refers to it */
+    pub fn close(&mut self, fd: usize) -> Result<(), VfsError> {
+        match self.files.get_mut(fd) {
+            Some(slot @ Some(_)) => {
+                *slot = None;
+                Ok(())
+            }
+            _ => Err(VfsError::BadDescriptor)
+        }
+    }
+
+    pub fn dup(&mut self, fd: usize) -> Result<usize, VfsError> {
+        let file = self.get(fd)?;
+        return self.insert(file);
+    }
+
+    /** Make `to` refer to the same file as `from`, closing whatever `to` had */
+    pub fn dup2(&mut self, from: usize, to: usize) -> Result<usize, VfsError> {
+        let file = self.get(from)?;
+        if to >= MAX_FILE_DESCRIPTORS {
+            return Err(VfsError::BadDescriptor);
+        }
+        if to >= self.files.len() {
+            self.files.resize(to + 1, None);
+        }
+        self.files[to] = Some(file);
+        return Ok(to);
+    }
+
+    pub fn close_all(&mut self) {
+        self.files.clear();
+    }
+}
@@ -0,0 +1,305 @@
+pub mod path;
+pub mod fd;
+pub mod component_fs;
+pub mod ramfs;
+pub mod devfs;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::RwLock;
+use crate::drivers::component_registry::{ComponentInfo, ComponentRegistry};
+use crate::drivers::filesystem::FsError;
+use crate::fs::component_fs::ComponentFs;
+use crate::fs::devfs::DevFs;
+use crate::fs::ramfs::RamFs;
+use crate::peripherals::eeprom::Eeprom;
+
+pub use crate::drivers::filesystem::{OpenMode, Whence};
+
+#[derive(Clone, Debug)]
+pub enum VfsError {
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    /// e.g. renaming across mounts, or writing to a read-only node
+    NotSupported,
+    AlreadyMounted,
+    NotMounted,
+    BadDescriptor,
+    TooManyOpenFiles,
+    /// e.g. a seek offset the filesystem can't represent
+    InvalidArgument,
+    Fs(FsError),
+}
+
+impl From<FsError> for VfsError {
+    fn from(e: FsError) -> Self {
+        VfsError::Fs(e)
+    }
+}
+
+impl fmt::Display for VfsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VfsError::Fs(e) => write!(f, "{}", e),
+            other => write!(f, "{:?}", other),
+        }
+    }
+}
+
+/** An open file. Closed when dropped. */
+pub trait File: Send {
+    /** Returns 0 at EOF */
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, VfsError>;
+    fn write(&mut self, buf: &[u8]) -> Result<usize, VfsError>;
+    /** Returns the new position */
+    fn seek(&mut self, whence: Whence, offset: i64) -> Result<u64, VfsError>;
+}
+
+/** Something that can be mounted. Paths are absolute and relative to the mount point. */
+pub trait FileSystem: Send + Sync {
+    /** Short description for the mount list */
+    fn describe(&self) -> String;
+    fn open(&self, path: &str, mode: OpenMode) -> Result<Box<dyn File>, VfsError>;
+    /** Directory entries end with '/' */
+    fn list(&self, path: &str) -> Result<Vec<String>, VfsError>;
+    fn exists(&self, path: &str) -> Result<bool, VfsError>;
+    fn is_directory(&self, path: &str) -> Result<bool, VfsError>;
+    fn size(&self, path: &str) -> Result<u64, VfsError>;
+    fn make_directory(&self, path: &str) -> Result<(), VfsError>;
+    fn remove(&self, path: &str) -> Result<(), VfsError>;
+    fn rename(&self, from: &str, to: &str) -> Result<(), VfsError>;
+}
+
+struct MountPoint {
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+pub struct Vfs {
+    mounts: Vec<MountPoint>
+}
+
+static GLOBAL_VFS: RwLock<Option<Vfs>> = RwLock::new(None);
+
+impl Vfs {
+    pub fn create_global() {
+        let current = GLOBAL_VFS.upgradeable_read();
+        if current.is_some() {
+            panic!("Global VFS already initialized")
+        }
+        let mut write = current.upgrade();
+        write.replace(Vfs { mounts: Vec::new() });
+    }
+
+    /** For mounting and unmounting. Lookups should use read_global, so they don't hold each other up. */
+    pub fn get_global<F, T>(f: F) -> T where F: Fn(&mut Vfs) -> T {
+        let mut lock = GLOBAL_VFS.write();
+        let vfs = lock.as_mut().unwrap();
+        f(vfs)
+    }
+
+    pub fn read_global<F, T>(f: F) -> T where F: Fn(&Vfs) -> T {
+        let lock = GLOBAL_VFS.read();
+        let vfs = lock.as_ref().unwrap();
+        f(vfs)
+    }
+}
+
+impl Vfs {
+    pub fn mount(&mut self, at: &str, fs: Arc<dyn FileSystem>) -> Result<(), VfsError> {
+        let at = path::normalize(at);
+        if self.mounts.iter().any(|m| m.path == at) {
+            return Err(VfsError::AlreadyMounted);
+        }
+        self.mounts.push(MountPoint { path: at, fs });
+        // Longest first, so resolve() finds the most specific mount
+        self.mounts.sort_by_key(|m| core::cmp::Reverse(m.path.len()));
+        return Ok(());
+    }
+
+    pub fn unmount(&mut self, at: &str) -> Result<(), VfsError> {
+        let at = path::normalize(at);
+        match self.mounts.iter().position(|m| m.path == at) {
+            Some(i) => {
+                self.mounts.remove(i);
+                Ok(())
+            }
+            None => Err(VfsError::NotMounted)
+        }
+    }
+
+    /** Find the filesystem for a path, and the path within it */
+    pub fn resolve(&self, path: &str) -> Result<(Arc<dyn FileSystem>, String), VfsError> {
+        let path = path::normalize(path);
+        for mount in &self.mounts {
+            if path::is_within(&path, &mount.path) {
+                return Ok((mount.fs.clone(), String::from(path::strip_prefix(&path, &mount.path))));
+            }
+        }
+        return Err(VfsError::NotMounted);
+    }
+
+    /** (mount point, description) pairs */
+    pub fn mounts(&self) -> Vec<(String, String)> {
+        self.mounts.iter().map(|m| (m.path.clone(), m.fs.describe())).collect()
+    }
+
+    /**
+     * Directory entries `dir` needs for the mount points under it: the mount point itself if it's directly
+     * inside, otherwise the first directory on the way to it
+     */
+    fn child_mounts(&self, dir: &str) -> Vec<String> {
+        let mut entries: Vec<String> = Vec::new();
+        for mount in self.mounts.iter().filter(|m| m.path != dir && path::is_within(&m.path, dir)) {
+            let relative = path::strip_prefix(&mount.path, dir);
+            let name = relative[1..].split('/').next().unwrap_or("");
+            let entry = format!("{}/", name);
+            if !entries.contains(&entry) {
+                entries.push(entry);
+            }
+        }
+        return entries;
+    }
+}
+
+pub fn open(path: &str, mode: OpenMode) -> Result<Box<dyn File>, VfsError> {
+    let (fs, relative) = Vfs::read_global(|vfs| vfs.resolve(path))?;
+    return fs.open(&relative, mode);
+}
+
+pub fn list(path: &str) -> Result<Vec<String>, VfsError> {
+    let normalized = path::normalize(path);
+    let (fs, relative) = Vfs::read_global(|vfs| vfs.resolve(&normalized))?;
+    let mounted = Vfs::read_global(|vfs| vfs.child_mounts(&normalized));
+
+    let mut entries = match fs.list(&relative) {
+        Ok(entries) => entries,
+        // Directories that only exist to hold mount points
+        Err(_) if !mounted.is_empty() => Vec::new(),
+        Err(e) => return Err(e)
+    };
+    for entry in mounted {
+        if !entries.contains(&entry) {
+            entries.push(entry);
+        }
+    }
+    return Ok(entries);
+}
+
+pub fn exists(path: &str) -> Result<bool, VfsError> {
+    let (fs, relative) = Vfs::read_global(|vfs| vfs.resolve(path))?;
+    return fs.exists(&relative);
+}
+
+pub fn is_directory(path: &str) -> Result<bool, VfsError> {
+    let (fs, relative) = Vfs::read_global(|vfs| vfs.resolve(path))?;
+    return fs.is_directory(&relative);
+}
+
+pub fn size(path: &str) -> Result<u64, VfsError> {
+    let (fs, relative) = Vfs::read_global(|vfs| vfs.resolve(path))?;
+    return fs.size(&relative);
+}
+
+pub fn make_directory(path: &str) -> Result<(), VfsError> {
+    let (fs, relative) = Vfs::read_global(|vfs| vfs.resolve(path))?;
+    return fs.make_directory(&relative);
+}
+
+pub fn remove(path: &str) -> Result<(), VfsError> {
+    let (fs, relative) = Vfs::read_global(|vfs| vfs.resolve(path))?;
+    return fs.remove(&relative);
+}
+
+pub fn rename(from: &str, to: &str) -> Result<(), VfsError> {
+    let (from_fs, from_relative) = Vfs::read_global(|vfs| vfs.resolve(from))?;
+    let (to_fs, to_relative) = Vfs::read_global(|vfs| vfs.resolve(to))?;
+    if !Arc::ptr_eq(&from_fs, &to_fs) {
+        return Err(VfsError::NotSupported);
+    }
+    return from_fs.rename(&from_relative, &to_relative);
+}
+
+/** Mount the boot filesystem at /, everything else under /mnt. Needs the component registry. */
+pub fn init() {
+    Vfs::create_global();
+
+    let boot_address = Eeprom::new().boot_address();
+    let filesystems: Vec<ComponentInfo> = ComponentRegistry::get_global(|registry| {
+        registry.find_by_type("filesystem").cloned().collect()
+    });
+
+    let root: Arc<dyn FileSystem> = match filesystems.iter().find(|c| Some(&c.address) == boot_address.as_ref()) {
+        Some(boot) => Arc::new(ComponentFs::new(&boot.address)),
+        // Nothing to boot from; at least give everyone somewhere to write
+        None => Arc::new(RamFs::new())
+    };
+
+    Vfs::get_global(|vfs| {
+        vfs.mount("/", root.clone()).unwrap();
+        vfs.mount("/dev", Arc::new(DevFs::new())).unwrap();
+        vfs.mount("/tmp", Arc::new(RamFs::new())).unwrap();
+        for component in &filesystems {
+            if Some(&component.address) == boot_address.as_ref() {
+                continue;
+            }
+            let at = format!("/mnt/{}", mount_name(&component.address, &filesystems));
+            vfs.mount(&at, Arc::new(ComponentFs::new(&component.address))).unwrap();
+        }
+    });
+}
+
+/** Shortest address prefix (at least 3 characters) that no other filesystem shares */
+fn mount_name<'a>(address: &'a str, filesystems: &[ComponentInfo]) -> &'a str {
+    for len in 3..address.len() {
+        let prefix = &address[..len];
+        let shared = filesystems.iter()
+            .filter(|c| c.address != address && c.address.starts_with(prefix))
+            .count();
+        if shared == 0 {
+            return prefix;
+        }
+    }
+    return address;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vfs(mounts: &[&str]) -> Vfs {
+        let mut vfs = Vfs { mounts: Vec::new() };
+        for at in mounts {
+            vfs.mount(at, Arc::new(RamFs::new())).unwrap();
+        }
+        vfs
+    }
+
+    #[test]
+    fn resolves_to_the_most_specific_mount() {
+        let vfs = vfs(&["/", "/mnt/abc", "/mnt"]);
+        let (_, relative) = vfs.resolve("/mnt/abc/file").unwrap();
+        assert_eq!(relative, "/file");
+        let (_, relative) = vfs.resolve("/mnt/abcd").unwrap();
+        assert_eq!(relative, "/abcd");
+    }
+
+    #[test]
+    fn listings_lead_to_nested_mounts() {
+        let vfs = vfs(&["/", "/dev", "/mnt/abc", "/mnt/def", "/mnt/def/inner"]);
+        let mut root = vfs.child_mounts("/");
+        root.sort();
+        assert_eq!(root, ["dev/", "mnt/"]);
+        let mut mnt = vfs.child_mounts("/mnt");
+        mnt.sort();
+        assert_eq!(mnt, ["abc/", "def/"]);
+        assert_eq!(vfs.child_mounts("/mnt/def"), ["inner/"]);
+        assert!(vfs.child_mounts("/dev").is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/** Collapse ".", ".." and repeated slashes. The result is always absolute, with no trailing slash. */
+pub fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                // Going above the root just stays at the root
+                parts.pop();
+            }
+            part => parts.push(part)
+        }
+    }
+
+    if parts.is_empty() {
+        return String::from("/");
+    }
+    let mut out = String::with_capacity(path.len());
+    for part in parts {
+        out.push('/');
+        out.push_str(part);
+    }
+    return out;
+}
+
+/** Resolve `path` against `base` unless it's already absolute */
+pub fn join(base: &str, path: &str) -> String {
+    if path.starts_with('/') {
+        return normalize(path);
+    }
+    return normalize(&format!("{}/{}", base, path));
+}
+
+/** Parent directory of a normalized path; the root is its own parent */
+pub fn parent(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(i) => &path[..i]
+    }
+}
+
+/** Last component of a normalized path; empty for the root */
+pub fn file_name(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(i) => &path[i + 1..],
+        None => path
+    }
+}
+
+/** Whether normalized `path` is `dir` or somewhere under it */
+pub fn is_within(path: &str, dir: &str) -> bool {
+    if dir == "/" {
+        return true;
+    }
+    return path == dir || (path.starts_with(dir) && path.as_bytes()[dir.len()] == b'/');
+}
+
+/** `path` relative to `dir`, as an absolute path. `path` must be within `dir`. */
+pub fn strip_prefix<'a>(path: &'a str, dir: &str) -> &'a str {
+    if dir == "/" {
+        return path;
+    }
+    if path == dir {
+        return "/";
+    }
+    return &path[dir.len()..];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_collapses_dots_and_slashes() {
+        assert_eq!(normalize(""), "/");
+        assert_eq!(normalize("/"), "/");
+        assert_eq!(normalize("//usr///bin/"), "/usr/bin");
+        assert_eq!(normalize("/usr/./bin/."), "/usr/bin");
+        assert_eq!(normalize("/usr/lib/../bin"), "/usr/bin");
+        assert_eq!(normalize("relative/path"), "/relative/path");
+    }
+
+    #[test]
+    fn normalize_stops_at_the_root() {
+        assert_eq!(normalize("/.."), "/");
+        assert_eq!(normalize("/../../etc"), "/etc");
+        assert_eq!(normalize("/a/../../b"), "/b");
+    }
+
+    #[test]
+    fn join_resolves_relative_paths() {
+        assert_eq!(join("/home", "file"), "/home/file");
+        assert_eq!(join("/home/user", "../other"), "/home/other");
+        assert_eq!(join("/home", "/etc/passwd"), "/etc/passwd");
+        assert_eq!(join("/", "."), "/");
+    }
+
+    #[test]
+    fn parent_and_file_name() {
+        assert_eq!(parent("/usr/bin"), "/usr");
+        assert_eq!(parent("/usr"), "/");
+        assert_eq!(parent("/"), "/");
+        assert_eq!(file_name("/usr/bin"), "bin");
+        assert_eq!(file_name("/"), "");
+    }
+
+    #[test]
+    fn is_within_respects_component_boundaries() {
+        assert!(is_within("/mnt", "/mnt"));
+        assert!(is_within("/mnt/disk", "/mnt"));
+        assert!(!is_within("/mntx", "/mnt"));
+        assert!(!is_within("/", "/mnt"));
+        assert!(is_within("/anything", "/"));
+    }
+
+    #[test]
+    fn strip_prefix_keeps_paths_absolute() {
+        assert_eq!(strip_prefix("/mnt/disk/file", "/mnt/disk"), "/file");
+        assert_eq!(strip_prefix("/mnt/disk", "/mnt/disk"), "/");
+        assert_eq!(strip_prefix("/etc", "/"), "/etc");
+    }
+}
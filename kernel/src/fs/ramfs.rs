@@ -0,0 +1,214 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::fs::{File, FileSystem, OpenMode, VfsError, Whence};
+use crate::fs::path;
+
+#[derive(Clone)]
+enum RamNode {
+    File(Arc<Mutex<Vec<u8>>>),
+    Directory,
+}
+
+/** In-memory filesystem. Contents are gone when it's unmounted. */
+pub struct RamFs {
+    /// Keyed by normalized path; the root always exists
+    nodes: Mutex<BTreeMap<String, RamNode>>
+}
+
+pub struct RamFile {
+    data: Arc<Mutex<Vec<u8>>>,
+    pos: usize,
+    writable: bool,
+}
+
+impl RamFs {
+    pub fn new() -> RamFs {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(String::from("/"), RamNode::Directory);
+        RamFs {
+            nodes: Mutex::new(nodes)
+        }
+    }
+
+    fn check_parent(nodes: &BTreeMap<String, RamNode>, path: &str) -> Result<(), VfsError> {
+        match nodes.get(path::parent(path)) {
+            Some(RamNode::Directory) => Ok(()),
+            Some(RamNode::File(_)) => Err(VfsError::NotADirectory),
+            None => Err(VfsError::NotFound)
+        }
+    }
+}
+
+impl Default for RamFs {
+    fn default() -> Self {
+        RamFs::new()
+    }
+}
+
+impl File for RamFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let data = self.data.lock();
+        if self.pos >= data.len() {
+            return Ok(0);
+        }
+        let len = (data.len() - self.pos).min(buf.len());
+        buf[..len].copy_from_slice(&data[self.pos..self.pos + len]);
+        self.pos += len;
+        return Ok(len);
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, VfsError> {
+        if !self.writable {
+            return Err(VfsError::NotSupported);
+        }
+        let mut data = self.data.lock();
+        let end = self.pos + buf.len();
+        if end > data.len() {
+            data.resize(end, 0);
+        }
+        data[self.pos..end].copy_from_slice(buf);
+        self.pos = end;
+        return Ok(buf.len());
+    }
+
+    fn seek(&mut self, whence: Whence, offset: i64) -> Result<u64, VfsError> {
+        let base = match whence {
+            Whence::Set => 0,
+            Whence::Cur => self.pos as i64,
+            Whence::End => self.data.lock().len() as i64,
+        };
+        self.pos = (base + offset).max(0) as usize;
+        return Ok(self.pos as u64);
+    }
+}
+
+impl FileSystem for RamFs {
+    fn describe(&self) -> String {
+        String::from("ramfs")
+    }
+
+    fn open(&self, path: &str, mode: OpenMode) -> Result<Box<dyn File>, VfsError> {
+        let path = path::normalize(path);
+        let mut nodes = self.nodes.lock();
+        let existing = nodes.get(&path).cloned();
+
+        let (data, pos) = match (existing, mode) {
+            (Some(RamNode::Directory), _) => return Err(VfsError::IsADirectory),
+            (None, OpenMode::Read) => return Err(VfsError::NotFound),
+            (Some(RamNode::File(data)), OpenMode::Read) => (data, 0),
+            (Some(RamNode::File(data)), OpenMode::Write) => {
+                data.lock().clear();
+                (data, 0)
+            }
+            (Some(RamNode::File(data)), OpenMode::Append) => {
+                let len = data.lock().len();
+                (data, len)
+            }
+            (None, _) => {
+                RamFs::check_parent(&nodes, &path)?;
+                let data = Arc::new(Mutex::new(Vec::new()));
+                nodes.insert(path, RamNode::File(data.clone()));
+                (data, 0)
+            }
+        };
+
+        return Ok(Box::new(RamFile {
+            data,
+            pos,
+            writable: mode != OpenMode::Read,
+        }));
+    }
+
+    fn list(&self, path: &str) -> Result<Vec<String>, VfsError> {
+        let path = path::normalize(path);
+        let nodes = self.nodes.lock();
+        match nodes.get(&path) {
+            Some(RamNode::Directory) => {}
+            Some(RamNode::File(_)) => return Err(VfsError::NotADirectory),
+            None => return Err(VfsError::NotFound)
+        }
+
+        return Ok(nodes.iter()
+            .filter(|(child, _)| **child != path && path::parent(child) == path)
+            .map(|(child, node)| match node {
+                RamNode::Directory => format!("{}/", path::file_name(child)),
+                RamNode::File(_) => String::from(path::file_name(child)),
+            })
+            .collect());
+    }
+
+    fn exists(&self, path: &str) -> Result<bool, VfsError> {
+        return Ok(self.nodes.lock().contains_key(&path::normalize(path)));
+    }
+
+    fn is_directory(&self, path: &str) -> Result<bool, VfsError> {
+        return Ok(matches!(self.nodes.lock().get(&path::normalize(path)), Some(RamNode::Directory)));
+    }
+
+    fn size(&self, path: &str) -> Result<u64, VfsError> {
+        return match self.nodes.lock().get(&path::normalize(path)) {
+            Some(RamNode::File(data)) => Ok(data.lock().len() as u64),
+            Some(RamNode::Directory) => Ok(0),
+            None => Err(VfsError::NotFound)
+        };
+    }
+
+    fn make_directory(&self, path: &str) -> Result<(), VfsError> {
+        let path = path::normalize(path);
+        let mut nodes = self.nodes.lock();
+        if nodes.contains_key(&path) {
+            return Err(VfsError::AlreadyExists);
+        }
+        RamFs::check_parent(&nodes, &path)?;
+        nodes.insert(path, RamNode::Directory);
+        return Ok(());
+    }
+
+    fn remove(&self, path: &str) -> Result<(), VfsError> {
+        let path = path::normalize(path);
+        if path == "/" {
+            return Err(VfsError::NotSupported);
+        }
+        let mut nodes = self.nodes.lock();
+        if !nodes.contains_key(&path) {
+            return Err(VfsError::NotFound);
+        }
+        if nodes.keys().any(|child| *child != path && path::is_within(child, &path)) {
+            return Err(VfsError::DirectoryNotEmpty);
+        }
+        nodes.remove(&path);
+        return Ok(());
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), VfsError> {
+        let from = path::normalize(from);
+        let to = path::normalize(to);
+        if from == "/" || path::is_within(&to, &from) {
+            return Err(VfsError::NotSupported);
+        }
+        let mut nodes = self.nodes.lock();
+        if !nodes.contains_key(&from) {
+            return Err(VfsError::NotFound);
+        }
+        if nodes.contains_key(&to) {
+            return Err(VfsError::AlreadyExists);
+        }
+        RamFs::check_parent(&nodes, &to)?;
+
+        // Move the node and everything under it
+        let moved: Vec<String> = nodes.keys()
+            .filter(|child| path::is_within(child, &from))
+            .cloned()
+            .collect();
+        for old in moved {
+            let node = nodes.remove(&old).unwrap();
+            let new = format!("{}{}", to, &old[from.len()..]);
+            nodes.insert(new, node);
+        }
+        return Ok(());
+    }
+}
@@ -0,0 +1,56 @@
+use core::fmt;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU8, Ordering};
+use crate::drivers::console::Console;
+use crate::peripherals::basic_fifo::BasicFIFO;
+
+/** Where kprint!/kprintln! output goes */
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Output {
+    Fifo,
+    Screen,
+    Both,
+}
+
+static OUTPUT: AtomicU8 = AtomicU8::new(Output::Fifo as u8);
+
+pub fn set_output(output: Output) {
+    OUTPUT.store(output as u8, Ordering::Relaxed);
+}
+
+pub fn output() -> Output {
+    match OUTPUT.load(Ordering::Relaxed) {
+        1 => Output::Screen,
+        2 => Output::Both,
+        _ => Output::Fifo
+    }
+}
+
+/**
+ * Don't kprint to the screen while holding the component client; the console needs it to draw.
+ * Output meant for the screen goes to the FIFO instead if there's no console (yet), or it's busy.
+ */
+pub fn _print(args: fmt::Arguments) {
+    let output = output();
+    let mut to_fifo = output != Output::Screen;
+    if output != Output::Fifo {
+        let drawn = Console::try_get_global(|console| console.write_fmt(args).is_ok());
+        if drawn != Some(true) {
+            to_fifo = true;
+        }
+    }
+    if to_fifo {
+        let _ = BasicFIFO::print_fifo().write_fmt(args);
+    }
+}
+
+#[macro_export]
+macro_rules! kprint {
+    ($($arg:tt)*) => ($crate::kprint::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! kprintln {
+    () => ($crate::kprint!("\n"));
+    ($($arg:tt)*) => ($crate::kprint!("{}\n", format_args!($($arg)*)));
+}
@@ -0,0 +1,523 @@
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem::size_of;
+use goblin::elf32::header::{Header, SIZEOF_EHDR, ELFMAG, SELFMAG, EI_CLASS, ELFCLASS32, EI_DATA, ELFDATA2LSB, ET_EXEC, EM_RISCV};
+use goblin::elf32::program_header::{ProgramHeader, SIZEOF_PHDR, PT_LOAD, PT_PHDR, PF_R, PF_W, PF_X};
+use crate::fs::{File, OpenMode, VfsError, Whence};
+use crate::mmu::page_allocator::{PageAllocator, PageRange};
+use crate::mmu::page_tables::{MMUManager, PageMapping, PagePermissions, VirtualMemorySpace, PAGE_SIZE, USER_END_PAGE};
+use crate::mmu::vma::{AreaKind, FaultAccess, VirtualMemoryArea};
+use crate::trap::frame::TrapFrame;
+
+/// The user stack sits at the very top of the user region, backed on demand
+pub const USER_STACK_PAGES: usize = 16;
+/// Unbacked pages below the stack, so an overflow faults instead of running into whatever's below
+pub const USER_STACK_GUARD_PAGES: usize = 1;
+/// PT_LOAD segments have to end below the stack and its guard
+const SEGMENT_END_PAGE: usize = USER_END_PAGE - USER_STACK_PAGES - USER_STACK_GUARD_PAGES;
+/// Keeps a runaway argv/envp from eating the whole stack
+const MAX_ARGUMENT_BYTES: usize = 4 * PAGE_SIZE;
+
+// Auxiliary vector entry types
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+
+/// sstatus.SPIE: interrupts come back on after the sret into the program
+const SSTATUS_SPIE: usize = 1 << 5;
+
+#[derive(Clone, Debug)]
+pub enum LoadError {
+    Io(VfsError),
+    NotElf,
+    /// A valid ELF, but not one we can run (wrong class, machine, type...)
+    Unsupported(&'static str),
+    /// Program header index
+    BadSegment(usize),
+    ArgumentsTooLong,
+    NoAddressSpace,
+    OutOfMemory,
+}
+
+impl From<VfsError> for LoadError {
+    fn from(e: VfsError) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "{}", e),
+            LoadError::NotElf => write!(f, "not an ELF file"),
+            LoadError::Unsupported(why) => write!(f, "unsupported ELF: {}", why),
+            LoadError::BadSegment(index) => write!(f, "bad program header {}", index),
+            LoadError::ArgumentsTooLong => write!(f, "arguments too long"),
+            LoadError::NoAddressSpace => write!(f, "no free address space"),
+            LoadError::OutOfMemory => write!(f, "out of memory"),
+        }
+    }
+}
+
+/** A program loaded into its own address space, not yet running */
+#[derive(Copy, Clone, Debug)]
+pub struct LoadedProgram {
+    /// MMUManager space id
+    pub space: usize,
+    pub entry: usize,
+    pub stack_pointer: usize,
+    pub argc: usize,
+    /// User addresses of the argv and envp arrays on the stack
+    pub argv: usize,
+    pub envp: usize,
+}
+
+impl LoadedProgram {
+    /**
+     * Registers to sret into the program with: U-mode at the entry point, with the stack from load().
+     * a0-a2 also get argc/argv/envp, for programs without a libc to dig them off the stack.
+     */
+    pub fn initial_frame(&self) -> TrapFrame {
+        let mut frame = TrapFrame {
+            regs: [0; 32],
+            pc: self.entry,
+            // SPP clear means user mode
+            status: SSTATUS_SPIE,
+            tval: 0,
+            cause: 0,
+        };
+        frame.regs[2] = self.stack_pointer;
+        frame.set_arg(0, self.argc);
+        frame.set_arg(1, self.argv);
+        frame.set_arg(2, self.envp);
+        return frame;
+    }
+}
+
+/** Open and load an executable by path */
+pub fn load_path(path: &str, argv: &[&str], envp: &[&str]) -> Result<LoadedProgram, LoadError> {
+    let mut file = crate::fs::open(path, OpenMode::Read)?;
+    return load(file.as_mut(), argv, envp);
+}
+
+/**
+ * Load an ELF32 RISC-V executable into a fresh address space: every PT_LOAD mapped with its own
+ * permissions, .bss zeroed, and a stack holding argc, argv, envp and the auxiliary vector.
+ */
+pub fn load(file: &mut dyn File, argv: &[&str], envp: &[&str]) -> Result<LoadedProgram, LoadError> {
+    let image = read_all(file)?;
+    let header = parse_header(&image)?;
+    let segments = parse_program_headers(&image, &header)?;
+    if !entry_is_executable(&header, &segments) {
+        return Err(LoadError::Unsupported("entry point isn't in an executable segment"));
+    }
+
+    MMUManager::get_global(|mmu| {
+        let id = mmu.allocate_address_space().ok_or(LoadError::NoAddressSpace)?;
+        let result = build_space(mmu, id, &image, &header, &segments, argv, envp);
+        if result.is_err() {
+            mmu.destroy_address_space(id);
+        }
+        return result;
+    })
+}
+
+fn build_space(
+    mmu: &mut MMUManager, id: usize, image: &[u8], header: &Header, segments: &[ProgramHeader],
+    argv: &[&str], envp: &[&str],
+) -> Result<LoadedProgram, LoadError> {
+    let space = mmu.get_space(id).ok_or(LoadError::NoAddressSpace)?;
+
+    for (index, segment) in segments.iter().enumerate().filter(|(_, s)| s.p_type == PT_LOAD) {
+        load_segment(mmu, space, image, index, segment)?;
+    }
+
+    let stack_end = USER_END_PAGE;
+    let stack = PageRange { start: stack_end - USER_STACK_PAGES, end: stack_end };
+    let guard = PageRange { start: stack.start - USER_STACK_GUARD_PAGES, end: stack.start };
+    let stack_permissions = PagePermissions { user: true, read: true, write: true, execute: false };
+    let stack_added = space.add_area(VirtualMemoryArea { pages: stack, permissions: stack_permissions, kind: AreaKind::Anonymous })
+        && space.add_area(VirtualMemoryArea { pages: guard, permissions: stack_permissions, kind: AreaKind::Guard });
+    if !stack_added {
+        // Segments always end below the guard, so this is only allocation failing
+        return Err(LoadError::OutOfMemory);
+    }
+
+    let entry = header.e_entry as usize;
+    let mut auxv: Vec<(usize, usize)> = Vec::new();
+    if let Some(phdr) = find_phdr(header, segments) {
+        auxv.push((AT_PHDR, phdr));
+    }
+    auxv.push((AT_PHENT, SIZEOF_PHDR));
+    auxv.push((AT_PHNUM, segments.len()));
+    auxv.push((AT_PAGESZ, PAGE_SIZE));
+    auxv.push((AT_ENTRY, entry));
+    auxv.push((AT_NULL, 0));
+
+    let (stack_pointer, argv_address, envp_address) = build_stack(mmu, space, stack_end * PAGE_SIZE, argv, envp, &auxv)?;
+    return Ok(LoadedProgram {
+        space: id,
+        entry,
+        stack_pointer,
+        argc: argv.len(),
+        argv: argv_address,
+        envp: envp_address,
+    });
+}
+
+fn read_all(file: &mut dyn File) -> Result<Vec<u8>, LoadError> {
+    file.seek(Whence::Set, 0)?;
+    let mut image = Vec::new();
+    let mut chunk = [0u8; 512];
+    loop {
+        let count = file.read(&mut chunk)?;
+        if count == 0 {
+            return Ok(image);
+        }
+        image.extend_from_slice(&chunk[..count]);
+    }
+}
+
+fn parse_header(image: &[u8]) -> Result<Header, LoadError> {
+    if image.len() < SIZEOF_EHDR || &image[..SELFMAG] != ELFMAG {
+        return Err(LoadError::NotElf);
+    }
+    // The image is just bytes, so don't assume it's aligned
+    let header = unsafe { (image.as_ptr() as *const Header).read_unaligned() };
+    if header.e_ident[EI_CLASS] != ELFCLASS32 {
+        return Err(LoadError::Unsupported("not 32 bit"));
+    }
+    if header.e_ident[EI_DATA] != ELFDATA2LSB {
+        return Err(LoadError::Unsupported("not little endian"));
+    }
+    if header.e_machine != EM_RISCV {
+        return Err(LoadError::Unsupported("not RISC-V"));
+    }
+    if header.e_type != ET_EXEC {
+        return Err(LoadError::Unsupported("not an executable"));
+    }
+    if header.e_phentsize as usize != SIZEOF_PHDR {
+        return Err(LoadError::Unsupported("odd program header size"));
+    }
+    return Ok(header);
+}
+
+fn parse_program_headers(image: &[u8], header: &Header) -> Result<Vec<ProgramHeader>, LoadError> {
+    let start = header.e_phoff as usize;
+    let count = header.e_phnum as usize;
+    // e_phoff comes straight from the file, so this can't be allowed to wrap
+    let end = count.checked_mul(SIZEOF_PHDR).and_then(|size| start.checked_add(size));
+    if end.is_none_or(|end| end > image.len()) {
+        return Err(LoadError::NotElf);
+    }
+    return Ok((0..count).map(|i| unsafe {
+        (image.as_ptr().add(start + i * SIZEOF_PHDR) as *const ProgramHeader).read_unaligned()
+    }).collect());
+}
+
+/** Where the program headers end up in memory, if they're loaded at all */
+fn find_phdr(header: &Header, segments: &[ProgramHeader]) -> Option<usize> {
+    if let Some(phdr) = segments.iter().find(|s| s.p_type == PT_PHDR) {
+        return Some(phdr.p_vaddr as usize);
+    }
+    let offset = header.e_phoff;
+    return segments.iter()
+        .filter(|s| s.p_type == PT_LOAD)
+        .find(|s| offset >= s.p_offset && offset - s.p_offset < s.p_filesz)
+        .and_then(|s| s.p_vaddr.checked_add(offset - s.p_offset))
+        .map(|address| address as usize);
+}
+
+/** Whether e_entry lands inside a PT_LOAD segment that's executable */
+fn entry_is_executable(header: &Header, segments: &[ProgramHeader]) -> bool {
+    let entry = header.e_entry;
+    return segments.iter()
+        .filter(|s| s.p_type == PT_LOAD && s.p_flags & PF_X != 0)
+        .any(|s| entry >= s.p_vaddr && entry - s.p_vaddr < s.p_memsz);
+}
+
+/** The pages a (non-empty) PT_LOAD segment covers, after checking it fits in the file and below the stack */
+fn segment_pages(image: &[u8], index: usize, segment: &ProgramHeader) -> Result<PageRange, LoadError> {
+    let vaddr = segment.p_vaddr as usize;
+    let file_size = segment.p_filesz as usize;
+    let mem_size = segment.p_memsz as usize;
+    let offset = segment.p_offset as usize;
+    let in_file = offset.checked_add(file_size).is_some_and(|end| end <= image.len());
+    let end = match vaddr.checked_add(mem_size) {
+        Some(end) if in_file && file_size <= mem_size => end,
+        _ => return Err(LoadError::BadSegment(index))
+    };
+    let pages = PageRange { start: vaddr / PAGE_SIZE, end: end.div_ceil(PAGE_SIZE) };
+    if !VirtualMemorySpace::is_user_page(pages.start) || pages.end > SEGMENT_END_PAGE {
+        return Err(LoadError::BadSegment(index));
+    }
+    return Ok(pages);
+}
+
+fn load_segment(mmu: &mut MMUManager, space: &VirtualMemorySpace, image: &[u8], index: usize, segment: &ProgramHeader) -> Result<(), LoadError> {
+    let vaddr = segment.p_vaddr as usize;
+    let file_size = segment.p_filesz as usize;
+    let mem_size = segment.p_memsz as usize;
+    let offset = segment.p_offset as usize;
+    if mem_size == 0 {
+        return Ok(());
+    }
+    let pages = segment_pages(image, index, segment)?;
+
+    let permissions = PagePermissions {
+        user: true,
+        read: segment.p_flags & PF_R != 0,
+        write: segment.p_flags & PF_W != 0,
+        execute: segment.p_flags & PF_X != 0,
+    };
+    for page in pages.start..pages.end {
+        match space.lookup(page) {
+            // Shared with the previous segment; it needs both sets of permissions
+            Some(existing) => {
+                let merged = PagePermissions {
+                    user: true,
+                    read: existing.read || permissions.read,
+                    write: existing.write || permissions.write,
+                    execute: existing.execute || permissions.execute,
+                };
+                if !space.protect_page(mmu, page, merged) {
+                    return Err(LoadError::OutOfMemory);
+                }
+            }
+            None => {
+                let frame = PageAllocator::get_global(|pg| pg.allocate()).map_err(|_| LoadError::OutOfMemory)?;
+                let mapped = space.map_page(mmu, PageMapping {
+                    src: page,
+                    dest: frame,
+                    user: permissions.user,
+                    read: permissions.read,
+                    write: permissions.write,
+                    execute: permissions.execute,
+                });
+                if !mapped {
+                    let _ = PageAllocator::get_global(|pg| pg.deallocate(frame));
+                    return Err(LoadError::OutOfMemory);
+                }
+            }
+        }
+    }
+
+    // Frames come zeroed, but a page shared with another segment might not be
+    write_user(mmu, space, vaddr, &image[offset..offset + file_size])?;
+    zero_user(mmu, space, vaddr + file_size, mem_size - file_size)?;
+    return Ok(());
+}
+
+/**
+ * Lay out the initial stack, top down: strings, then (16-byte aligned) argc, argv, NULL, envp, NULL and the
+ * auxv pairs. Returns the stack pointer and the argv and envp addresses.
+ */
+fn build_stack(
+    mmu: &mut MMUManager, space: &VirtualMemorySpace, top: usize, argv: &[&str], envp: &[&str],
+    auxv: &[(usize, usize)],
+) -> Result<(usize, usize, usize), LoadError> {
+    let string_bytes: usize = argv.iter().chain(envp.iter()).map(|s| s.len() + 1).sum();
+    if string_bytes > MAX_ARGUMENT_BYTES {
+        return Err(LoadError::ArgumentsTooLong);
+    }
+
+    let mut cursor = top;
+    let mut push_strings = |strings: &[&str]| -> Result<Vec<usize>, LoadError> {
+        let mut addresses = Vec::with_capacity(strings.len());
+        for string in strings {
+            cursor -= string.len() + 1;
+            write_user(mmu, space, cursor, string.as_bytes())?;
+            write_user(mmu, space, cursor + string.len(), &[0])?;
+            addresses.push(cursor);
+        }
+        return Ok(addresses);
+    };
+    let envp_strings = push_strings(envp)?;
+    let argv_strings = push_strings(argv)?;
+
+    let mut table: Vec<usize> = Vec::new();
+    table.push(argv.len());
+    table.extend_from_slice(&argv_strings);
+    table.push(0);
+    table.extend_from_slice(&envp_strings);
+    table.push(0);
+    for (key, value) in auxv {
+        table.push(*key);
+        table.push(*value);
+    }
+
+    let table_bytes = table.len() * size_of::<usize>();
+    let stack_pointer = (cursor - table_bytes) & !0xF;
+    let bytes: Vec<u8> = table.iter().flat_map(|word| word.to_le_bytes()).collect();
+    write_user(mmu, space, stack_pointer, &bytes)?;
+
+    let argv_address = stack_pointer + size_of::<usize>();
+    let envp_address = argv_address + (argv.len() + 1) * size_of::<usize>();
+    return Ok((stack_pointer, argv_address, envp_address));
+}
+
+/** Copy into a user space through the frames' physical addresses, backing area pages as needed */
+fn write_user(mmu: &mut MMUManager, space: &VirtualMemorySpace, vaddr: usize, data: &[u8]) -> Result<(), LoadError> {
+    return for_each_user_chunk(mmu, space, vaddr, data.len(), |dest, done| {
+        dest.copy_from_slice(&data[done..done + dest.len()]);
+    });
+}
+
+fn zero_user(mmu: &mut MMUManager, space: &VirtualMemorySpace, vaddr: usize, len: usize) -> Result<(), LoadError> {
+    return for_each_user_chunk(mmu, space, vaddr, len, |dest, _| {
+        dest.iter_mut().for_each(|b| *b = 0);
+    });
+}
+
+/** Calls `f` with each page-sized (or smaller) piece of a user range, and how far into the range it starts */
+fn for_each_user_chunk<F>(mmu: &mut MMUManager, space: &VirtualMemorySpace, vaddr: usize, len: usize, mut f: F) -> Result<(), LoadError>
+    where F: FnMut(&mut [u8], usize) {
+    let mut done = 0;
+    while done < len {
+        let address = vaddr + done;
+        let page = address / PAGE_SIZE;
+        if !space.is_mapped(page) && space.handle_fault(mmu, page, FaultAccess::Write, true).is_err() {
+            return Err(LoadError::OutOfMemory);
+        }
+        let (paddr, _) = space.translate(address).ok_or(LoadError::OutOfMemory)?;
+        let count = (PAGE_SIZE - address % PAGE_SIZE).min(len - done);
+        let dest = unsafe {
+            core::slice::from_raw_parts_mut(paddr as *mut u8, count)
+        };
+        f(dest, done);
+        done += count;
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use goblin::elf32::header::{EI_VERSION, EV_CURRENT};
+    use super::*;
+
+    fn header(phoff: u32, phnum: u16) -> Header {
+        let mut header = Header::default();
+        header.e_ident[..SELFMAG].copy_from_slice(ELFMAG);
+        header.e_ident[EI_CLASS] = ELFCLASS32;
+        header.e_ident[EI_DATA] = ELFDATA2LSB;
+        header.e_ident[EI_VERSION] = EV_CURRENT;
+        header.e_type = ET_EXEC;
+        header.e_machine = EM_RISCV;
+        header.e_phoff = phoff;
+        header.e_phentsize = SIZEOF_PHDR as u16;
+        header.e_phnum = phnum;
+        header
+    }
+
+    /** The header, then `segments` as the program headers right after it */
+    fn image(segments: &[ProgramHeader]) -> Vec<u8> {
+        let header = header(SIZEOF_EHDR as u32, segments.len() as u16);
+        let mut image = vec![0u8; SIZEOF_EHDR + segments.len() * SIZEOF_PHDR];
+        unsafe {
+            (image.as_mut_ptr() as *mut Header).write_unaligned(header);
+            for (i, segment) in segments.iter().enumerate() {
+                (image.as_mut_ptr().add(SIZEOF_EHDR + i * SIZEOF_PHDR) as *mut ProgramHeader).write_unaligned(*segment);
+            }
+        }
+        image
+    }
+
+    fn segment(p_type: u32, offset: u32, vaddr: u32, size: u32) -> ProgramHeader {
+        ProgramHeader {
+            p_type,
+            p_offset: offset,
+            p_vaddr: vaddr,
+            p_filesz: size,
+            p_memsz: size,
+            p_flags: PF_R,
+            ..ProgramHeader::default()
+        }
+    }
+
+    #[test]
+    fn parses_program_headers() {
+        let segments = [segment(PT_LOAD, 0, 0x1_0000, 0x100), segment(PT_LOAD, 0x1000, 0x2_0000, 0x10)];
+        let image = image(&segments);
+        let header = parse_header(&image).unwrap();
+        let parsed = parse_program_headers(&image, &header).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert!(parsed[0] == segments[0] && parsed[1] == segments[1]);
+    }
+
+    #[test]
+    fn rejects_headers_past_the_end() {
+        let image = image(&[segment(PT_LOAD, 0, 0x1_0000, 0x100)]);
+        let one_too_many = header(SIZEOF_EHDR as u32, 2);
+        assert!(matches!(parse_program_headers(&image, &one_too_many), Err(LoadError::NotElf)));
+        let one_byte_late = header(SIZEOF_EHDR as u32 + 1, 1);
+        assert!(matches!(parse_program_headers(&image, &one_byte_late), Err(LoadError::NotElf)));
+    }
+
+    #[test]
+    fn rejects_offsets_that_would_wrap() {
+        let image = image(&[]);
+        for phoff in [u32::MAX, u32::MAX - SIZEOF_PHDR as u32 + 1].iter() {
+            let hostile = header(*phoff, u16::MAX);
+            assert!(matches!(parse_program_headers(&image, &hostile), Err(LoadError::NotElf)));
+        }
+    }
+
+    #[test]
+    fn no_program_headers_is_fine() {
+        let image = image(&[]);
+        let header = parse_header(&image).unwrap();
+        assert!(parse_program_headers(&image, &header).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_things_that_arent_riscv_executables() {
+        assert!(matches!(parse_header(&[0x7F, b'E', b'L']), Err(LoadError::NotElf)));
+        let mut image = image(&[]);
+        image[1] = b'X';
+        assert!(matches!(parse_header(&image), Err(LoadError::NotElf)));
+
+        let mut not_riscv = header(SIZEOF_EHDR as u32, 0);
+        not_riscv.e_machine = 0x3E;
+        let mut image = vec![0u8; SIZEOF_EHDR];
+        unsafe { (image.as_mut_ptr() as *mut Header).write_unaligned(not_riscv) };
+        assert!(matches!(parse_header(&image), Err(LoadError::Unsupported(_))));
+    }
+
+    #[test]
+    fn phdr_address_doesnt_overflow() {
+        let header = header(0x100, 1);
+        let near_top = [segment(PT_LOAD, 0, u32::MAX - 0x10, 0x1000)];
+        assert_eq!(find_phdr(&header, &near_top), None);
+        let normal = [segment(PT_LOAD, 0, 0x1_0000, 0x1000)];
+        assert_eq!(find_phdr(&header, &normal), Some(0x1_0100));
+    }
+
+    #[test]
+    fn segments_stay_below_the_stack_guard() {
+        let image = vec![0u8; PAGE_SIZE];
+        let last = ((SEGMENT_END_PAGE - 1) * PAGE_SIZE) as u32;
+        let fits = segment(PT_LOAD, 0, last, PAGE_SIZE as u32);
+        let pages = segment_pages(&image, 0, &fits).unwrap();
+        assert_eq!((pages.start, pages.end), (SEGMENT_END_PAGE - 1, SEGMENT_END_PAGE));
+        let into_guard = segment(PT_LOAD, 0, last + 1, PAGE_SIZE as u32);
+        assert!(matches!(segment_pages(&image, 0, &into_guard), Err(LoadError::BadSegment(0))));
+        let in_stack = segment(PT_LOAD, 0, ((USER_END_PAGE - 1) * PAGE_SIZE) as u32, 0x10);
+        assert!(matches!(segment_pages(&image, 0, &in_stack), Err(LoadError::BadSegment(0))));
+    }
+
+    #[test]
+    fn entry_has_to_be_executable() {
+        let mut header = header(SIZEOF_EHDR as u32, 2);
+        let data = segment(PT_LOAD, 0, 0x1_0000, 0x1000);
+        let text = ProgramHeader { p_flags: PF_R | PF_X, ..segment(PT_LOAD, 0x1000, 0x2_0000, 0x1000) };
+        header.e_entry = 0x2_0010;
+        assert!(entry_is_executable(&header, &[data, text]));
+        header.e_entry = 0x1_0010;
+        assert!(!entry_is_executable(&header, &[data, text]));
+        header.e_entry = 0x2_1000;
+        assert!(!entry_is_executable(&header, &[data, text]));
+    }
+}
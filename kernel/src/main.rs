@@ -1,26 +1,75 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 // TODO: remove these supressions (it's just to hide non-useful ones early in dev)
 #![allow(dead_code)]
 #![allow(unused_variables)]
 #![allow(unreachable_code)]
 // #![feature(const_generics)]
 
+#[cfg(not(test))]
 use riscv_rt::entry;
+#[cfg(not(test))]
 use core::panic::PanicInfo;
 use peripherals::basic_fifo::BasicFIFO;
+#[cfg(not(test))]
 use core::fmt::Write;
 
-//#[macro_use]
-//extern crate alloc;
+/// First user program, if the boot filesystem has one
+const INIT_PATH: &str = "/init";
+
+#[macro_use]
+extern crate alloc;
 
 #[macro_use]
 extern crate bitfield;
 
+#[cfg(not(test))]
 #[entry]
 fn main() -> ! {
+    use drivers::component_registry::ComponentRegistry;
+    use drivers::console::Console;
+    use drivers::gpu_driver::GPUDriver;
+    use fs::Vfs;
+    use process::scheduler::Scheduler;
+    use timer::wheel::TimerWheel;
+
     // do something here
+    trap::init();
+    sbi::firmware::init();
+    timer::init();
+    drivers::component_client::ComponentClient::create_global();
     mmu::setup_mmu();
+
+    match ComponentRegistry::create_global() {
+        Ok(()) => ComponentRegistry::get_global(|registry| {
+            for component in registry.all() {
+                kprintln!("Component {} {}", component.kind, component.address);
+            }
+        }),
+        Err(e) => panic!("Unable to list components: {}", e)
+    }
+
+    fs::init();
+
+    match GPUDriver::bind_first().and_then(Console::create_global) {
+        Ok(()) => kprint::set_output(kprint::Output::Both),
+        Err(e) => kprintln!("No console: {}", e)
+    }
+
+    for (at, fs) in Vfs::read_global(|vfs| vfs.mounts()) {
+        kprintln!("Mounted {} at {}", fs, at);
+    }
+
+    TimerWheel::create_global();
+    Scheduler::create_global();
+    match process::spawn_path(INIT_PATH, &[INIT_PATH], &[], None) {
+        Ok(pid) => {
+            kprintln!("Started {} as pid {}", INIT_PATH, pid);
+            process::scheduler::run();
+        }
+        Err(e) => kprintln!("Unable to start {}: {}", INIT_PATH, e)
+    }
+
     panic!("Kernel ended execution!")
 }
 
@@ -29,6 +78,7 @@ pub struct PanicOut {
 }
 
 
+#[cfg(not(test))]
 #[inline(never)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
@@ -64,6 +114,13 @@ fn panic(info: &PanicInfo) -> ! {
     }
 }
 
+pub mod kprint;
 pub mod peripherals;
 pub mod drivers;
-pub mod mmu;
\ No newline at end of file
+pub mod fs;
+pub mod mmu;
+pub mod loader;
+pub mod process;
+pub mod timer;
+pub mod sbi;
+pub mod trap;
\ No newline at end of file
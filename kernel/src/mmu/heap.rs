@@ -0,0 +1,264 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::size_of;
+use core::ptr::null_mut;
+use core::fmt::Write;
+use spin::Mutex;
+use crate::mmu::page_allocator::PageAllocator;
+use crate::mmu::page_tables::PAGE_SIZE;
+use crate::peripherals::basic_fifo::BasicFIFO;
+
+/// Object sizes served from slabs. Anything bigger gets whole pages.
+pub const SIZE_CLASSES: [usize; 7] = [16, 32, 64, 128, 256, 512, 1024];
+pub const CLASS_COUNT: usize = SIZE_CLASSES.len();
+
+#[cfg_attr(not(test), global_allocator)]
+static KERNEL_HEAP: KernelHeap = KernelHeap { inner: Mutex::new(None) };
+
+/// Lives at the end of each slab page; objects fill the page from the start
+#[repr(C)]
+struct SlabHeader {
+    prev: *mut SlabHeader,
+    next: *mut SlabHeader,
+    free: *mut FreeObject,
+    used: usize,
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct HeapStats {
+    /// Pages currently held as slabs
+    pub slab_pages: usize,
+    /// Pages currently held by large (> 1 KiB) allocations
+    pub large_pages: usize,
+    /// Bytes requested by live allocations
+    pub bytes_requested: usize,
+    /// Bytes actually handed out for live allocations (rounded up to size class or page)
+    pub bytes_in_use: usize,
+    pub allocations: usize,
+    pub deallocations: usize,
+    /// Live slabs for each entry in SIZE_CLASSES
+    pub slabs_per_class: [usize; CLASS_COUNT],
+}
+
+struct Heap {
+    /// Slabs with at least one free object, per size class. Full slabs aren't tracked.
+    partial: [*mut SlabHeader; CLASS_COUNT],
+    stats: HeapStats,
+}
+
+pub struct KernelHeap {
+    inner: Mutex<Option<Heap>>,
+}
+
+/**
+ * Must run after setup_mmu has identity mapped all of RAM into the kernel space. Heap pages come straight from
+ * the page allocator and aren't mapped individually, so they're only reachable through that mapping.
+ * Allocations before this fail.
+ */
+pub fn init() {
+    let mut heap = KERNEL_HEAP.inner.lock();
+    if heap.is_some() {
+        panic!("Kernel heap already initialized")
+    }
+    heap.replace(Heap {
+        partial: [null_mut(); CLASS_COUNT],
+        stats: HeapStats::default(),
+    });
+}
+
+pub fn stats() -> HeapStats {
+    match KERNEL_HEAP.inner.lock().as_ref() {
+        Some(heap) => heap.stats,
+        None => HeapStats::default()
+    }
+}
+
+fn size_class(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&class| class >= size)
+}
+
+fn pages_for(layout: &Layout) -> usize {
+    layout.size().div_ceil(PAGE_SIZE)
+}
+
+/** Pull pages from the page allocator. All of RAM is already identity mapped in kernel space. */
+fn acquire_pages(count: usize) -> Option<usize> {
+    let range = PageAllocator::get_global(|pg| pg.allocate_range(count)).ok()?;
+    return Some(range.start);
+}
+
+fn release_pages(start: usize, count: usize) {
+    // Stays mapped: all of RAM is identity mapped in kernel space, and the allocator poisons freed pages through it
+    PageAllocator::get_global(|pg| {
+        for page in start..(start + count) {
+            if let Err(e) = pg.deallocate(page) {
+                // Panicking inside dealloc helps nobody; leak it. Straight to the FIFO, since kprint might allocate.
+                let mut print_fifo = BasicFIFO::print_fifo();
+                let _ = writeln!(print_fifo, "Heap leaking page {:x} it couldn't release: {}", page, e);
+            }
+        }
+    });
+}
+
+impl Heap {
+    unsafe fn alloc_small(&mut self, class: usize) -> *mut u8 {
+        let mut slab = self.partial[class];
+        if slab.is_null() {
+            slab = match Heap::new_slab(SIZE_CLASSES[class]) {
+                Some(slab) => slab,
+                None => return null_mut()
+            };
+            self.push_partial(class, slab);
+            self.stats.slab_pages += 1;
+            self.stats.slabs_per_class[class] += 1;
+        }
+
+        let obj = (*slab).free;
+        (*slab).free = (*obj).next;
+        (*slab).used += 1;
+        if (*slab).free.is_null() {
+            self.unlink(class, slab);
+        }
+        return obj as *mut u8;
+    }
+
+    unsafe fn dealloc_small(&mut self, class: usize, ptr: *mut u8) {
+        let slab = Heap::slab_of(ptr);
+        let was_full = (*slab).free.is_null();
+
+        let obj = ptr as *mut FreeObject;
+        (*obj).next = (*slab).free;
+        (*slab).free = obj;
+        (*slab).used -= 1;
+
+        if was_full {
+            self.push_partial(class, slab);
+        }
+        if (*slab).used == 0 {
+            // Give the page back
+            self.unlink(class, slab);
+            release_pages(slab as usize / PAGE_SIZE, 1);
+            self.stats.slab_pages -= 1;
+            self.stats.slabs_per_class[class] -= 1;
+        }
+    }
+
+    unsafe fn new_slab(object_size: usize) -> Option<*mut SlabHeader> {
+        let page = acquire_pages(1)?;
+        let base = page * PAGE_SIZE;
+        let header_offset = PAGE_SIZE - size_of::<SlabHeader>();
+        let capacity = header_offset / object_size;
+
+        // Thread the free list through the objects
+        let mut free: *mut FreeObject = null_mut();
+        for i in (0..capacity).rev() {
+            let obj = (base + i * object_size) as *mut FreeObject;
+            (*obj).next = free;
+            free = obj;
+        }
+
+        let header = (base + header_offset) as *mut SlabHeader;
+        header.write(SlabHeader {
+            prev: null_mut(),
+            next: null_mut(),
+            free,
+            used: 0,
+        });
+        return Some(header);
+    }
+
+    fn slab_of(ptr: *mut u8) -> *mut SlabHeader {
+        let base = ptr as usize & !(PAGE_SIZE - 1);
+        (base + PAGE_SIZE - size_of::<SlabHeader>()) as *mut SlabHeader
+    }
+
+    unsafe fn push_partial(&mut self, class: usize, slab: *mut SlabHeader) {
+        let head = self.partial[class];
+        (*slab).prev = null_mut();
+        (*slab).next = head;
+        if !head.is_null() {
+            (*head).prev = slab;
+        }
+        self.partial[class] = slab;
+    }
+
+    unsafe fn unlink(&mut self, class: usize, slab: *mut SlabHeader) {
+        let prev = (*slab).prev;
+        let next = (*slab).next;
+        if prev.is_null() {
+            if self.partial[class] == slab {
+                self.partial[class] = next;
+            }
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+        (*slab).prev = null_mut();
+        (*slab).next = null_mut();
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut lock = self.inner.lock();
+        let heap = match lock.as_mut() {
+            Some(heap) => heap,
+            None => return null_mut()
+        };
+        if layout.align() > PAGE_SIZE {
+            return null_mut();
+        }
+
+        let (ptr, actual_size) = match size_class(&layout) {
+            Some(class) => (heap.alloc_small(class), SIZE_CLASSES[class]),
+            None => {
+                let pages = pages_for(&layout);
+                match acquire_pages(pages) {
+                    Some(page) => {
+                        heap.stats.large_pages += pages;
+                        ((page * PAGE_SIZE) as *mut u8, pages * PAGE_SIZE)
+                    }
+                    None => (null_mut(), 0)
+                }
+            }
+        };
+
+        if !ptr.is_null() {
+            heap.stats.allocations += 1;
+            heap.stats.bytes_requested += layout.size();
+            heap.stats.bytes_in_use += actual_size;
+        }
+        return ptr;
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut lock = self.inner.lock();
+        let heap = lock.as_mut().unwrap();
+
+        let actual_size = match size_class(&layout) {
+            Some(class) => {
+                heap.dealloc_small(class, ptr);
+                SIZE_CLASSES[class]
+            }
+            None => {
+                let pages = pages_for(&layout);
+                release_pages(ptr as usize / PAGE_SIZE, pages);
+                heap.stats.large_pages -= pages;
+                pages * PAGE_SIZE
+            }
+        };
+
+        heap.stats.deallocations += 1;
+        heap.stats.bytes_requested -= layout.size();
+        heap.stats.bytes_in_use -= actual_size;
+    }
+}
+
+// The raw slab pointers are only touched with the lock held
+unsafe impl Send for Heap {}
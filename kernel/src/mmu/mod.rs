@@ -1,12 +1,28 @@
-mod page_allocator;
+pub mod page_allocator;
 pub mod page_tables;
+pub mod heap;
+pub mod phys_map;
+pub mod tlb;
+pub mod vma;
 
 use crate::peripherals::basic_fifo::BasicFIFO;
 use core::fmt::Write;
 use crate::mmu::page_allocator::{PageAllocator, PageRange};
 use page_tables::PAGE_SIZE;
 use crate::mmu::page_tables::{MMUManager, PageMapping};
+use crate::mmu::phys_map::{PhysMemoryMap, RegionKind};
 use riscv::register;
+#[cfg(not(test))]
+use core::arch::asm;
+
+/// PMP setup only happens at boot, which host unit tests never do
+#[cfg(test)]
+macro_rules! asm {
+    ($($t:tt)*) => {{
+        unsafe fn host_test() -> ! { unreachable!("RISC-V assembly in a host test") }
+        host_test()
+    }}
+}
 
 extern "C" {
     // rx
@@ -81,81 +97,82 @@ pub fn setup_mmu() {
     let max_page = stack_pages.end - 1;
     let _ = writeln!(print_fifo, "Highest current page: {:x}", max_page);
 
-    // Create global page allocator
-    let alloc_pages = PageAllocator::create_global(start_page, max_page);
-    // The pages for the kernel have already been allocated
+    // Describe physical memory, then hand the usable part to the page allocator
+    PhysMemoryMap::create_global(PageRange { start: start_page, end: max_page + 1 });
+    let alloc_pages = PageAllocator::create_global();
+    PhysMemoryMap::get_global(|map| {
+        let mut print_fifo = BasicFIFO::print_fifo();
+        let _ = write!(print_fifo, "{}", map);
+    });
+    PageAllocator::get_global(|pg| {
+        let mut print_fifo = BasicFIFO::print_fifo();
+        let _ = writeln!(print_fifo, "Free pages: {}/{}, by order: {:?}", pg.free_pages(), pg.total_pages(), pg.free_counts());
+    });
 
     // Allocate a page for the mmu manager
-    if let Some(mmu_manager_page) = PageAllocator::get_global(|pg| pg.allocate()) {
-        MMUManager::create_global(mmu_manager_page..(mmu_manager_page + 1))
-    } else {
-        panic!("Unable to allocate a page for the MMU manager")
+    match PageAllocator::get_global(|pg| pg.allocate()) {
+        Ok(mmu_manager_page) => MMUManager::create_global(mmu_manager_page..(mmu_manager_page + 1)),
+        Err(e) => panic!("Unable to allocate a page for the MMU manager: {}", e)
     }
 
     // Map kernel space
     MMUManager::get_global(|mmu| {
         let kernel = mmu.get_kernel();
-        for page in text_pages.start..text_pages.end {
-            kernel.map_page(mmu, PageMapping {
-                src: page,
-                dest: page,
-                user: false,
-                read: true,
-                write: false,
-                execute: true,
-            });
-        }
-        for page in rodata_pages.start..rodata_pages.end {
-            kernel.map_page(mmu, PageMapping {
-                src: page,
-                dest: page,
+        let sections = [
+            (&text_pages, false, true),
+            (&rodata_pages, false, false),
+            (&data_pages, true, false),
+            (&stack_pages, true, false),
+            (&alloc_pages, true, false),
+        ];
+        for (pages, write, execute) in sections.iter() {
+            kernel.map_range(mmu, PageMapping {
+                src: pages.start,
+                dest: pages.start,
                 user: false,
                 read: true,
-                write: false,
-                execute: false,
-            });
-        }
-        for page in data_pages.start..data_pages.end {
-            kernel.map_page(mmu, PageMapping {
-                src: page,
-                dest: page,
-                user: false,
-                read: true,
-                write: true,
-                execute: false,
-            });
+                write: *write,
+                execute: *execute,
+            }, pages.len());
         }
-        for page in stack_pages.start..stack_pages.end {
-            kernel.map_page(mmu, PageMapping {
-                src: page,
-                dest: page,
-                user: false,
-                read: true,
-                write: true,
-                execute: false,
-            });
+    });
+
+    // Devices, and the rest of RAM so page tables and freshly allocated pages can be reached from S-mode
+    let memory_map = PhysMemoryMap::get_global(|map| map.clone());
+    MMUManager::get_global(|mmu| {
+        let kernel = mmu.get_kernel();
+        for region in memory_map.of_kind(RegionKind::Mmio) {
+            if !kernel.map_mmio(mmu, &region.pages()) {
+                panic!("Unable to map {}", region);
+            }
         }
-        for page in alloc_pages.start..alloc_pages.end {
-            kernel.map_page(mmu, PageMapping {
-                src: page,
-                dest: page,
+        memory_map.for_each_usable(|pages| {
+            kernel.map_range(mmu, PageMapping {
+                src: pages.start,
+                dest: pages.start,
                 user: false,
                 read: true,
                 write: true,
                 execute: false,
-            });
-        }
+            }, pages.len());
+        });
     });
 
+    // All of RAM is mapped now, which is where the heap's pages come from
+    heap::init();
+
     // Turn on the MMU mapping
     MMUManager::get_global(|mmu| mmu.enable(mmu.kernel_space_id()));
     // Now we have to swap to supervisor mode
     unsafe {
+        // Without a PMP entry, S-mode can't touch any physical memory at all. Let it see everything;
+        // the page tables do the real protection. (NAPOT over the whole address space, RWX)
+        asm!("csrw pmpaddr0, {}", in(reg) usize::MAX);
+        asm!("csrw pmpcfg0, {}", in(reg) 0x1F);
+        tlb::flush_all();
         // First, set supervisor
         register::mstatus::set_mpp(register::mstatus::MPP::Supervisor);
         // Then call mret
         _mret_direct();
     }
-
-    panic!("We probably forgot all our MMIO devices lmao")
 }
@@ -1,24 +1,134 @@
-use crate::peripherals::memory_size::MemorySize;
+use crate::mmu::phys_map::PhysMemoryMap;
 use bitvec::prelude::*;
 use bitvec::slice::bits_from_raw_parts_mut;
 use crate::mmu::page_tables::PAGE_SIZE;
 use bitvec::indices::BitIdx;
 use spin::RwLock;
+use core::fmt;
 
+/// Largest block is 2^MAX_ORDER pages: 4 MiB, one Sv32 megapage
+pub const MAX_ORDER: usize = 10;
+const ORDERS: usize = MAX_ORDER + 1;
+
+/// Freed pages are filled with this in checked mode, so use-after-free stands out
+pub const POISON_BYTE: u8 = 0xA5;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PageAllocError {
+    OutOfMemory,
+    /// Not a page this allocator manages
+    OutOfRange(usize),
+    /// Freeing a page that isn't allocated
+    DoubleFree(usize),
+    /// Freeing a page that isn't usable RAM (kernel image, allocator tables, ...)
+    Reserved(usize),
+    BadOrder(usize),
+    /// Freeing a frame that's still shared (ref count above 1)
+    StillShared(usize),
+    /// Too many references to one frame
+    TooManyReferences(usize),
+}
+
+impl fmt::Display for PageAllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PageAllocError::OutOfMemory => write!(f, "out of physical pages"),
+            PageAllocError::OutOfRange(page) => write!(f, "page {:x} is out of range", page),
+            PageAllocError::DoubleFree(page) => write!(f, "page {:x} freed twice", page),
+            PageAllocError::Reserved(page) => write!(f, "page {:x} is reserved", page),
+            PageAllocError::BadOrder(order) => write!(f, "order {} is too big", order),
+            PageAllocError::StillShared(page) => write!(f, "page {:x} is still shared", page),
+            PageAllocError::TooManyReferences(page) => write!(f, "page {:x} has too many references", page),
+        }
+    }
+}
+
+/**
+ * Buddy allocator over physical pages. Blocks of 2^order pages are aligned to their size, relative to
+ * page_base.
+ *
+ * alloc_table has a bit per page saying whether it's in use. free_table has a bitmap per order, with a
+ * bit per block saying whether that whole block is free (and not part of a bigger free block).
+ * ref_counts has a count per page, for frames shared between address spaces: 1 when allocated, and
+ * release() only frees a frame when the last reference goes.
+ *
+ * In checked mode (always on in debug builds), frees are checked for double frees and reserved pages,
+ * and freed pages are poisoned.
+ */
 #[repr(C)]
 pub struct PageAllocator<'a> {
     total_pages: usize,
     page_base: usize,
     alloc_table: &'a mut BitSlice<Msb0, u8>,
-    next_open_page: usize,
+    free_table: &'a mut BitSlice<Msb0, u8>,
+    ref_counts: &'a mut [u16],
+    /// Where each order's bitmap starts in free_table
+    order_offsets: [usize; ORDERS],
+    free_counts: [usize; ORDERS],
+    /// No free block of that order has a lower index
+    search_from: [usize; ORDERS],
+    /// As of create_global; only its usable RAM can ever be freed
+    memory_map: PhysMemoryMap,
+    checked: bool,
 }
 
+#[derive(Copy, Clone, Debug)]
 pub struct PageRange { pub start: usize, pub end: usize }
 
+impl PageRange {
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.end <= self.start
+    }
+
+    pub fn contains(&self, page: usize) -> bool {
+        page >= self.start && page < self.end
+    }
+}
+
+/** Byte offsets of the allocator's tables, for a given amount of RAM */
+struct TableLayout {
+    /// Where each order's bitmap starts in the free table, in bits
+    order_offsets: [usize; ORDERS],
+    free_bits: usize,
+    alloc_bytes: usize,
+    ref_count_offset: usize,
+    bytes: usize,
+}
+
+impl TableLayout {
+    fn new(mem_pages: usize) -> TableLayout {
+        // A bit per page to track allocation status, plus the free bitmaps
+        let mut order_offsets = [0; ORDERS];
+        let mut free_bits = 0;
+        for (order, offset) in order_offsets.iter_mut().enumerate() {
+            *offset = free_bits;
+            free_bits += PageAllocator::blocks_in(mem_pages, order);
+        }
+        let alloc_bytes = mem_pages.div_ceil(8);
+        // Ref counts go last, 2-byte aligned
+        let ref_count_offset = (alloc_bytes + free_bits.div_ceil(8)).next_multiple_of(2);
+        return TableLayout {
+            order_offsets,
+            free_bits,
+            alloc_bytes,
+            ref_count_offset,
+            bytes: ref_count_offset + mem_pages * 2,
+        };
+    }
+}
+
 static GLOBAL_PAGE_ALLOCATOR: RwLock<Option<PageAllocator>> = RwLock::new(None);
 
 impl PageAllocator<'_> {
-    pub fn create_global(base_page: usize, max_page: usize) -> PageRange {
+    /**
+     * Manage the physical memory map's main memory. Everything that isn't usable RAM starts out
+     * allocated. Returns the pages used for the allocator's own tables, which are added to the map.
+     */
+    pub fn create_global() -> PageRange {
         let current = GLOBAL_PAGE_ALLOCATOR.upgradeable_read();
         if current.is_some() {
             panic!("Global page allocator already initialized")
@@ -26,37 +136,69 @@ impl PageAllocator<'_> {
         let mut write = current.upgrade();
 
         // Figure out size of main ram
-        let mem_size = MemorySize::new().max_size();
-        let mem_pages = mem_size / PAGE_SIZE;
+        let ram = PhysMemoryMap::get_global(|map| map.main_memory().map(|ram| ram.pages()))
+            .expect("No RAM in the physical memory map");
+        let table_size = TableLayout::new(ram.len()).bytes.div_ceil(PAGE_SIZE);
 
-        // Figure out how many pages of bits we need to track allocation status
-        let table_size = mem_pages / 8 / PAGE_SIZE + if mem_pages % (8 * PAGE_SIZE) > 0 { 1 } else { 0 };
-        let alloc_pages = PageRange { start: max_page + 1, end: max_page + table_size + 1 };
+        // Put the tables in the first usable spot they fit
+        let alloc_pages = PhysMemoryMap::get_global(|map| {
+            let mut found = None;
+            map.for_each_usable(|usable| {
+                if found.is_none() && usable.len() >= table_size {
+                    found = Some(PageRange { start: usable.start, end: usable.start + table_size });
+                }
+            });
+            let found = found.expect("No room for the page allocator's tables");
+            map.reserve("page allocator", &found);
+            found
+        });
+        let memory_map = PhysMemoryMap::get_global(|map| map.clone());
+
+        let table = (alloc_pages.start * PAGE_SIZE) as *mut u8;
+        write.replace(unsafe { PageAllocator::new(ram, memory_map, table) });
+
+        return alloc_pages;
+    }
+
+    /**
+     * Manage `ram`, keeping the tables at `table` (TableLayout::new(ram.len()).bytes of them, 2-byte
+     * aligned). Only pages the map says are usable start out free.
+     */
+    unsafe fn new(ram: PageRange, memory_map: PhysMemoryMap, table: *mut u8) -> PageAllocator<'static> {
+        let base_page = ram.start;
+        let mem_pages = ram.len();
+        let TableLayout { order_offsets, free_bits, alloc_bytes, ref_count_offset, .. } = TableLayout::new(mem_pages);
 
-        // Create page allocator
         let mut new = PageAllocator {
             page_base: base_page,
             total_pages: mem_pages,
             alloc_table: unsafe {
-                bits_from_raw_parts_mut(
-                    (alloc_pages.start * PAGE_SIZE) as *mut u8,
-                    BitIdx::new(0).unwrap(),
-                    mem_pages,
-                )
+                bits_from_raw_parts_mut(table, BitIdx::new(0).unwrap(), mem_pages)
+            },
+            free_table: unsafe {
+                bits_from_raw_parts_mut(table.add(alloc_bytes), BitIdx::new(0).unwrap(), free_bits)
+            },
+            ref_counts: unsafe {
+                core::slice::from_raw_parts_mut(table.add(ref_count_offset) as *mut u16, mem_pages)
             },
-            next_open_page: alloc_pages.end,
+            order_offsets,
+            free_counts: [0; ORDERS],
+            search_from: [0; ORDERS],
+            memory_map,
+            checked: cfg!(debug_assertions),
         };
 
-        // Zero out the memory
-        new.alloc_table.set_all(false);
+        // Everything is allocated until the map says otherwise
+        new.alloc_table.set_all(true);
+        new.free_table.set_all(false);
+        new.ref_counts.iter_mut().for_each(|count| *count = 0);
 
-        for page in base_page..alloc_pages.end {
-            new.write_bit(page, true)
-        }
-
-        write.replace(new);
-
-        return alloc_pages;
+        let memory_map = new.memory_map.clone();
+        memory_map.for_each_usable(|usable| {
+            new.mark_allocated(usable.start, usable.len(), false);
+            new.release_entries(usable.start - base_page, usable.end - base_page);
+        });
+        return new;
     }
 
     pub fn get_global<F, T>(f: F) -> T where F: Fn(&mut PageAllocator) -> T {
@@ -67,66 +209,379 @@ impl PageAllocator<'_> {
 }
 
 impl PageAllocator<'_> {
+    /** None for pages outside the managed range */
     #[inline]
-    fn page_entry(&self, page: usize) -> usize {
-        return page - self.page_base;
+    fn page_entry(&self, page: usize) -> Option<usize> {
+        let entry = page.checked_sub(self.page_base)?;
+        if entry >= self.total_pages {
+            return None;
+        }
+        return Some(entry);
     }
 
     fn read_bit(&self, page: usize) -> bool {
-        let entry = self.page_entry(page);
-        if entry > self.total_pages {
-            return true;
+        match self.page_entry(page) {
+            Some(entry) => self.alloc_table[entry],
+            // Not ours, so never free
+            None => true
         }
-        self.alloc_table[entry]
     }
 
     fn write_bit(&mut self, page: usize, value: bool) {
-        let entry = self.page_entry(page);
-        if entry > self.total_pages {
-            return;
+        if let Some(entry) = self.page_entry(page) {
+            self.alloc_table.set(entry, value)
         }
-        self.alloc_table.set(entry, value)
     }
 
     pub fn is_allocated(&self, page: usize) -> bool {
         return self.read_bit(page);
     }
 
-    pub fn allocate(&mut self) -> Option<usize> {
-        if self.page_entry(self.next_open_page) > self.total_pages {
-            None
-        } else {
-            let page = self.next_open_page;
-            // find next open page
-            // TODO: optimize
-            loop {
-                self.next_open_page += 1;
-                if self.page_entry(self.next_open_page) > self.total_pages || !self.read_bit(self.next_open_page) {
-                    break;
+    pub fn is_checked(&self) -> bool {
+        return self.checked;
+    }
+
+    /** Turn checked mode on or off. Debug builds are always checked. */
+    pub fn set_checked(&mut self, checked: bool) {
+        self.checked = checked || cfg!(debug_assertions);
+    }
+
+    pub fn allocate(&mut self) -> Result<usize, PageAllocError> {
+        return self.allocate_order(0);
+    }
+
+    pub fn deallocate(&mut self, page: usize) -> Result<(), PageAllocError> {
+        return self.deallocate_order(page, 0);
+    }
+
+    /** Allocate 2^order contiguous, zeroed pages, aligned to their size. Returns the first page. */
+    pub fn allocate_order(&mut self, order: usize) -> Result<usize, PageAllocError> {
+        let entry = self.take_block(order)?;
+        let page = self.page_base + entry;
+        self.mark_allocated(page, 1 << order, true);
+        PageAllocator::fill(page, 1 << order, 0);
+        return Ok(page);
+    }
+
+    /** Free a block from allocate_order(order). Nothing is freed if any page fails the checks. */
+    pub fn deallocate_order(&mut self, page: usize, order: usize) -> Result<(), PageAllocError> {
+        if order > MAX_ORDER {
+            return Err(PageAllocError::BadOrder(order));
+        }
+        let count = 1 << order;
+        let entry = self.page_entry(page).ok_or(PageAllocError::OutOfRange(page))?;
+        if self.page_entry(page + count - 1).is_none() || entry % count != 0 {
+            return Err(PageAllocError::OutOfRange(page));
+        }
+        if self.checked {
+            for p in page..(page + count) {
+                if !self.memory_map.is_usable_page(p) {
+                    return Err(PageAllocError::Reserved(p));
+                }
+                if !self.read_bit(p) {
+                    return Err(PageAllocError::DoubleFree(p));
+                }
+                if self.ref_count(p) > 1 {
+                    return Err(PageAllocError::StillShared(p));
                 }
             }
+            PageAllocator::fill(page, count, POISON_BYTE);
+        }
+
+        self.mark_allocated(page, count, false);
+        self.release_block(entry, order);
+        return Ok(());
+    }
+
+    pub fn ref_count(&self, page: usize) -> usize {
+        match self.page_entry(page) {
+            Some(entry) => self.ref_counts[entry] as usize,
+            None => 0
+        }
+    }
+
+    /** Take another reference to an allocated frame */
+    pub fn retain(&mut self, page: usize) -> Result<(), PageAllocError> {
+        let entry = self.page_entry(page).ok_or(PageAllocError::OutOfRange(page))?;
+        if self.ref_counts[entry] == 0 {
+            // Free, or not something we handed out (reserved pages have no count)
+            return Err(if self.read_bit(page) { PageAllocError::Reserved(page) } else { PageAllocError::DoubleFree(page) });
+        }
+        self.ref_counts[entry] = self.ref_counts[entry].checked_add(1)
+            .ok_or(PageAllocError::TooManyReferences(page))?;
+        return Ok(());
+    }
+
+    /** Drop a reference to a frame, freeing it if that was the last. Returns whether it was freed. */
+    pub fn release(&mut self, page: usize) -> Result<bool, PageAllocError> {
+        let entry = self.page_entry(page).ok_or(PageAllocError::OutOfRange(page))?;
+        if self.ref_counts[entry] > 1 {
+            self.ref_counts[entry] -= 1;
+            return Ok(false);
+        }
+        self.deallocate(page)?;
+        return Ok(true);
+    }
+
+    /**
+     * Allocate `count` physically contiguous, zeroed pages. Whatever the block has beyond `count` goes
+     * straight back, and the pages can be freed one at a time with deallocate().
+     */
+    pub fn allocate_range(&mut self, count: usize) -> Result<PageRange, PageAllocError> {
+        let order = PageAllocator::order_for(count)?;
+        let entry = self.take_block(order)?;
+        self.release_entries(entry + count, entry + (1 << order));
+
+        let start = self.page_base + entry;
+        self.mark_allocated(start, count, true);
+        PageAllocator::fill(start, count, 0);
+        Ok(PageRange { start, end: start + count })
+    }
+
+    /** Smallest order that fits `count` pages */
+    pub fn order_for(count: usize) -> Result<usize, PageAllocError> {
+        let order = count.max(1).next_power_of_two().trailing_zeros() as usize;
+        if order > MAX_ORDER {
+            return Err(PageAllocError::BadOrder(order));
+        }
+        return Ok(order);
+    }
+
+    /** Free blocks of each order */
+    pub fn free_counts(&self) -> [usize; ORDERS] {
+        return self.free_counts;
+    }
+
+    pub fn free_pages(&self) -> usize {
+        return self.free_counts.iter().enumerate().map(|(order, count)| count << order).sum();
+    }
+
+    pub fn total_pages(&self) -> usize {
+        return self.total_pages;
+    }
+
+    fn blocks_in(pages: usize, order: usize) -> usize {
+        return pages >> order;
+    }
+
+    fn is_free(&self, index: usize, order: usize) -> bool {
+        return self.free_table[self.order_offsets[order] + index];
+    }
+
+    fn set_free(&mut self, index: usize, order: usize, free: bool) {
+        self.free_table.set(self.order_offsets[order] + index, free);
+        if free {
+            self.free_counts[order] += 1;
+            self.search_from[order] = self.search_from[order].min(index);
+        } else {
+            self.free_counts[order] -= 1;
+        }
+    }
 
-            self.write_bit(page, true);
+    /** Find a free block of `order`, splitting a bigger one if needed. Returns its first entry. */
+    fn take_block(&mut self, order: usize) -> Result<usize, PageAllocError> {
+        if order > MAX_ORDER {
+            return Err(PageAllocError::BadOrder(order));
+        }
+        let found = (order..ORDERS)
+            .find(|o| self.free_counts[*o] > 0)
+            .ok_or(PageAllocError::OutOfMemory)?;
+
+        let offset = self.order_offsets[found];
+        let blocks = PageAllocator::blocks_in(self.total_pages, found);
+        let start = self.search_from[found];
+        let mut index = start + self.free_table[(offset + start)..(offset + blocks)]
+            .iter()
+            .position(|free| *free)
+            .expect("Free count says there's a block, but the bitmap doesn't");
+        self.set_free(index, found, false);
+        self.search_from[found] = index + 1;
+
+        // Split, keeping the lower half and freeing the upper
+        for split in (order..found).rev() {
+            index <<= 1;
+            self.set_free(index + 1, split, true);
+        }
+        return Ok(index << order);
+    }
+
+    /** Mark a block free, merging it with its buddy as far up as possible */
+    fn release_block(&mut self, entry: usize, order: usize) {
+        let mut index = entry >> order;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = index ^ 1;
+            if buddy >= PageAllocator::blocks_in(self.total_pages, order) || !self.is_free(buddy, order) {
+                break;
+            }
+            self.set_free(buddy, order, false);
+            index >>= 1;
+            order += 1;
+        }
+        self.set_free(index, order, true);
+    }
 
-            // Zero the page
-            let ptr = (page * PAGE_SIZE) as *mut u8;
-            unsafe {
-                ptr.write_bytes(0, PAGE_SIZE);
+    /** Free entries start..end as the biggest aligned blocks that fit */
+    fn release_entries(&mut self, start: usize, end: usize) {
+        let mut entry = start;
+        while entry < end {
+            let mut order = 0;
+            while order < MAX_ORDER
+                && entry.is_multiple_of(1 << (order + 1))
+                && entry + (1 << (order + 1)) <= end {
+                order += 1;
             }
+            self.release_block(entry, order);
+            entry += 1 << order;
+        }
+    }
 
-            Some(page)
+    fn mark_allocated(&mut self, page: usize, count: usize, value: bool) {
+        for p in page..(page + count) {
+            self.write_bit(p, value);
+            if let Some(entry) = self.page_entry(p) {
+                self.ref_counts[entry] = value as u16;
+            }
         }
     }
 
-    pub fn deallocate(&mut self, page: usize) {
-        if page < self.next_open_page {
-            self.next_open_page = page;
+    fn fill(page: usize, count: usize, value: u8) {
+        let ptr = (page * PAGE_SIZE) as *mut u8;
+        unsafe {
+            ptr.write_bytes(value, count * PAGE_SIZE);
         }
-        self.write_bit(page, false);
     }
 }
 
 // Mark it as send/sync (we are careful to make sure it's safe, since we are in charge of the pointer)
 unsafe impl Sync for PageAllocator<'_> {}
 
-unsafe impl Send for PageAllocator<'_> {}
\ No newline at end of file
+unsafe impl Send for PageAllocator<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use crate::mmu::phys_map::{MemoryRegion, RegionKind};
+    use super::*;
+
+    /** An allocator over `pages` pages of leaked host memory, with `reserved` (relative pages) taken out */
+    fn allocator(pages: usize, reserved: &[usize]) -> PageAllocator<'static> {
+        let memory = Box::leak(vec![0u8; (pages + 1) * PAGE_SIZE].into_boxed_slice());
+        let start = (memory.as_ptr() as usize).div_ceil(PAGE_SIZE);
+        let ram = PageRange { start, end: start + pages };
+        let mut map = PhysMemoryMap::default();
+        map.add(MemoryRegion {
+            name: "test ram",
+            kind: RegionKind::Ram,
+            start: ram.start * PAGE_SIZE,
+            end: ram.end * PAGE_SIZE,
+            read: true,
+            write: true,
+            execute: false,
+            cacheable: true,
+        });
+        for page in reserved {
+            map.reserve("test", &PageRange { start: start + page, end: start + page + 1 });
+        }
+        let tables = Box::leak(vec![0u16; TableLayout::new(pages).bytes.div_ceil(2)].into_boxed_slice());
+        return unsafe { PageAllocator::new(ram, map, tables.as_mut_ptr() as *mut u8) };
+    }
+
+    fn byte_at(page: usize) -> u8 {
+        unsafe { *((page * PAGE_SIZE) as *const u8) }
+    }
+
+    #[test]
+    fn starts_as_the_biggest_blocks_that_fit() {
+        let pg = allocator(13, &[]);
+        assert_eq!(pg.free_pages(), 13);
+        // 8 + 4 + 1
+        assert_eq!(&pg.free_counts()[..4], &[1, 0, 1, 1]);
+    }
+
+    #[test]
+    fn splits_then_merges_back() {
+        let mut pg = allocator(16, &[]);
+        assert_eq!(&pg.free_counts()[..5], &[0, 0, 0, 0, 1]);
+        let page = pg.allocate().unwrap();
+        assert_eq!(&pg.free_counts()[..5], &[1, 1, 1, 1, 0]);
+        pg.deallocate(page).unwrap();
+        assert_eq!(&pg.free_counts()[..5], &[0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn blocks_are_aligned_to_their_size() {
+        let mut pg = allocator(32, &[]);
+        let base = pg.page_base;
+        pg.allocate().unwrap();
+        for order in 0..4 {
+            let page = pg.allocate_order(order).unwrap();
+            assert_eq!((page - base) % (1 << order), 0, "order {}", order);
+        }
+        assert_eq!(pg.allocate_order(MAX_ORDER + 1), Err(PageAllocError::BadOrder(MAX_ORDER + 1)));
+    }
+
+    #[test]
+    fn ranges_give_back_the_rest_of_their_block() {
+        let mut pg = allocator(16, &[]);
+        let range = pg.allocate_range(3).unwrap();
+        assert_eq!(range.len(), 3);
+        assert_eq!(pg.free_pages(), 13);
+        for page in range.start..range.end {
+            pg.deallocate(page).unwrap();
+        }
+        assert_eq!(&pg.free_counts()[..5], &[0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn runs_out_then_recovers() {
+        let mut pg = allocator(5, &[]);
+        let pages: Vec<usize> = (0..5).map(|_| pg.allocate().unwrap()).collect();
+        assert_eq!(pg.allocate(), Err(PageAllocError::OutOfMemory));
+        for page in pages {
+            pg.deallocate(page).unwrap();
+        }
+        assert_eq!(pg.free_pages(), 5);
+        assert!(pg.allocate_order(2).is_ok());
+    }
+
+    #[test]
+    fn freed_pages_are_poisoned_and_reallocated_zeroed() {
+        let mut pg = allocator(1, &[]);
+        let page = pg.allocate().unwrap();
+        assert_eq!(byte_at(page), 0);
+        pg.deallocate(page).unwrap();
+        assert_eq!(byte_at(page), POISON_BYTE);
+        assert_eq!(pg.allocate(), Ok(page));
+        assert_eq!(byte_at(page), 0);
+    }
+
+    #[test]
+    fn checked_frees_catch_mistakes() {
+        let mut pg = allocator(8, &[3]);
+        let base = pg.page_base;
+        assert_eq!(pg.free_pages(), 7);
+        let page = pg.allocate().unwrap();
+        pg.deallocate(page).unwrap();
+        assert_eq!(pg.deallocate(page), Err(PageAllocError::DoubleFree(page)));
+        assert_eq!(pg.deallocate(base + 3), Err(PageAllocError::Reserved(base + 3)));
+        assert_eq!(pg.deallocate(base + 8), Err(PageAllocError::OutOfRange(base + 8)));
+        // A misaligned block
+        assert_eq!(pg.deallocate_order(base + 1, 1), Err(PageAllocError::OutOfRange(base + 1)));
+    }
+
+    #[test]
+    fn shared_frames_free_on_last_release() {
+        let mut pg = allocator(4, &[]);
+        let page = pg.allocate().unwrap();
+        pg.retain(page).unwrap();
+        assert_eq!(pg.ref_count(page), 2);
+        assert_eq!(pg.deallocate(page), Err(PageAllocError::StillShared(page)));
+        assert_eq!(pg.release(page), Ok(false));
+        assert!(pg.is_allocated(page));
+        assert_eq!(pg.release(page), Ok(true));
+        assert!(!pg.is_allocated(page));
+        assert_eq!(pg.retain(page), Err(PageAllocError::DoubleFree(page)));
+    }
+}
@@ -1,13 +1,70 @@
+use alloc::boxed::Box;
 use spin::RwLock;
 use core::mem::size_of;
 use core::ops::Range;
-use crate::mmu::page_allocator::PageAllocator;
+use crate::mmu::page_allocator::{PageAllocator, PageRange};
+use crate::mmu::tlb;
+use crate::mmu::vma::{AreaKind, AreaList, FaultAccess, FaultError, VirtualMemoryArea};
 use crate::peripherals::basic_fifo::BasicFIFO;
 use core::fmt::Write;
 use riscv::register::satp;
 
 pub const PAGE_SIZE: usize = 4096;
 pub const PAGE_TABLE_SIZE: usize = PAGE_SIZE / 4;
+/// Pages covered by one root entry: a 4 MiB megapage
+pub const MEGAPAGE_PAGES: usize = PAGE_TABLE_SIZE;
+/// Allocator order of a megapage's worth of frames
+pub const MEGAPAGE_ORDER: usize = 10;
+
+/**
+ * Every space is split in two. The user region sits below the MMIO windows, and skips page 0 so null
+ * stays unmapped. Everything from USER_END_PAGE up (devices, then all of RAM) is the kernel's: its root
+ * entries are global and copied into every space, so traps don't need to switch satp.
+ */
+pub const USER_START_PAGE: usize = 1;
+pub const USER_END_PAGE: usize = 0x1000_0000 / PAGE_SIZE;
+/// First root entry that belongs to the kernel
+const KERNEL_ROOT_START: usize = USER_END_PAGE / MEGAPAGE_PAGES;
+
+/// asid_generation of the kernel's space, which always has ASID 0
+const KERNEL_GENERATION: usize = usize::MAX;
+
+/// rsw bit for a user page that's shared copy-on-write: read-only in the tables, but really writable
+const RSW_COW: u32 = 0b01;
+
+/// Log every map_page call to the print FIFO
+const DEBUG_MAPPINGS: bool = false;
+
+bitfield! {
+    /// riscv 0.6 only has satp::Mode and satp::set on RISC-V targets, so go through the raw bits
+    struct Satp(u32);
+    impl Debug;
+    #[inline]
+    mode_sv32, set_mode_sv32: 31;
+    #[inline]
+    asid, set_asid: 30, 22;
+    #[inline]
+    ppn, set_ppn: 21, 0;
+}
+
+impl Satp {
+    fn read() -> Satp {
+        return Satp(satp::read().bits() as u32);
+    }
+
+    /** Sv32 translation through the root table at `ppn` */
+    fn sv32(asid: usize, ppn: usize) -> Satp {
+        let mut value = Satp(0);
+        value.set_mode_sv32(true);
+        value.set_asid(asid as u32);
+        value.set_ppn(ppn as u32);
+        return value;
+    }
+
+    unsafe fn write(&self) {
+        satp::write(self.0 as usize);
+    }
+}
 
 bitfield! {
     pub struct PageTableEntry(u32);
@@ -42,10 +99,50 @@ pub type PageTable = [PageTableEntry; PAGE_TABLE_SIZE];
 
 #[repr(C)]
 pub struct VirtualMemorySpace {
-    pub root_page: usize
+    pub root_page: usize,
+    /// Hardware ASID; only means anything while asid_generation matches the manager's
+    asid: usize,
+    asid_generation: usize,
+    /// Lazily backed regions. Zeroed memory is None, so this is created on first use (which is after the heap exists).
+    areas: Option<Box<AreaList>>,
+    stats: AccessStats,
+}
+
+/** Page counts from one scan_access */
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AccessStats {
+    /// Mapped pages
+    pub resident: usize,
+    /// Touched since the previous scan: the working set
+    pub accessed: usize,
+    pub dirty: usize,
+    /// Scans so far
+    pub scans: usize,
+}
+
+impl AccessStats {
+    /** Count a leaf, and clear its accessed bit */
+    fn count(&mut self, entry: &mut PageTableEntry, pages: usize) {
+        self.resident += pages;
+        if entry.a() {
+            self.accessed += pages;
+        }
+        if entry.d() {
+            self.dirty += pages;
+        }
+        entry.set_a(false);
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PagePermissions {
+    pub user: bool,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct PageMapping {
     pub src: usize,
     pub dest: usize,
@@ -55,39 +152,82 @@ pub struct PageMapping {
     pub execute: bool,
 }
 
+impl PageMapping {
+    pub fn permissions(&self) -> PagePermissions {
+        PagePermissions {
+            user: self.user,
+            read: self.read,
+            write: self.write,
+            execute: self.execute,
+        }
+    }
+}
+
 impl VirtualMemorySpace {
     pub fn is_initialized(&self) -> bool {
         self.root_page != 0
     }
 
-    /** Must run in machine mode */
-    pub fn map_page(&self, mmu: &mut MMUManager, mapping: PageMapping) -> bool {
-        let mut print_fifo = BasicFIFO::print_fifo();
-        let _ = writeln!(print_fifo, "Request to map page {:x?}", mapping);
+    pub fn is_kernel(&self) -> bool {
+        self.asid_generation == KERNEL_GENERATION
+    }
+
+    pub fn is_user_page(page: usize) -> bool {
+        (USER_START_PAGE..USER_END_PAGE).contains(&page)
+    }
+
+    /** The kernel only maps its own region, and user spaces only theirs; the kernel region is shared */
+    fn owns_page(&self, page: usize) -> bool {
+        VirtualMemorySpace::is_user_page(page) != self.is_kernel()
+    }
 
+    /**
+     * Page tables are accessed by physical address, so this needs machine mode or RAM identity mapped
+     * (which setup_mmu does before leaving machine mode)
+     */
+    pub fn map_page(&self, mmu: &mut MMUManager, mapping: PageMapping) -> bool {
+        if !self.owns_page(mapping.src) {
+            return false;
+        }
         let src_superpage = mapping.src / PAGE_TABLE_SIZE;
         let src_childpage = mapping.src % PAGE_TABLE_SIZE;
-        let dest_superpage = mapping.dest / PAGE_TABLE_SIZE;
-        let dest_childpage = mapping.dest % PAGE_TABLE_SIZE;
 
-        let _ = writeln!(print_fifo, "Src: {:x}:{:x}", src_superpage, src_childpage);
-        let _ = writeln!(print_fifo, "Dest: {:x}:{:x}", dest_superpage, dest_childpage);
+        if DEBUG_MAPPINGS {
+            let mut print_fifo = BasicFIFO::print_fifo();
+            let _ = writeln!(print_fifo, "Request to map page {:x?}", mapping);
+            let _ = writeln!(print_fifo, "Src: {:x}:{:x}", src_superpage, src_childpage);
+            let _ = writeln!(print_fifo, "Dest: {:x}:{:x}", mapping.dest / PAGE_TABLE_SIZE, mapping.dest % PAGE_TABLE_SIZE);
+        }
 
         // Get the superpage
         let root_page_table = self.get_root_page_table();
         let superpage_entry = &mut root_page_table[src_superpage];
 
+        if VirtualMemorySpace::is_leaf(superpage_entry) {
+            // Already covered by a megapage; only split it if this page is actually changing
+            let existing = VirtualMemorySpace::entry_mapping(superpage_entry, mapping.src, superpage_entry.ppn() as usize + src_childpage);
+            if existing == mapping {
+                return true;
+            }
+            if !self.split_megapage(mmu, src_superpage) {
+                return false;
+            }
+        }
+        let superpage_entry = &mut root_page_table[src_superpage];
+
         // Get or allocate the child page offset
         let childpage_entry_page = if superpage_entry.v() {
             superpage_entry.ppn() as usize
         } else {
-            if let Some(page) = PageAllocator::get_global(|pg| pg.allocate()) {
+            if let Ok(page) = PageAllocator::get_global(|pg| pg.allocate()) {
                 superpage_entry.set_ppn(page as u32);
                 superpage_entry.set_x(false);
                 superpage_entry.set_r(false);
                 superpage_entry.set_w(false);
                 superpage_entry.set_u(true); // ignored, and subpages may be user-accessible
+                superpage_entry.set_g(self.is_kernel());
                 superpage_entry.set_v(true);
+                mmu.share_kernel_entry(self, src_superpage);
                 page
             } else {
                 return false;
@@ -98,16 +238,539 @@ impl VirtualMemorySpace {
             &mut *((childpage_entry_page * PAGE_SIZE) as *mut PageTable)
         };
         let child_page_entry = &mut childpage_table[src_childpage];
-        child_page_entry.set_ppn(mapping.dest as u32);
-        child_page_entry.set_x(mapping.execute);
-        child_page_entry.set_r(mapping.read);
-        child_page_entry.set_w(mapping.write);
-        child_page_entry.set_u(mapping.user);
-        child_page_entry.set_v(true);
+        let was_valid = child_page_entry.v();
+        VirtualMemorySpace::set_leaf(child_page_entry, mapping.dest, &mapping, self.is_kernel());
+        if was_valid {
+            self.flush_page(mapping.src);
+        }
+
+        return true;
+    }
+
+    /**
+     * Map 4 MiB with a single root entry. src and dest must be megapage aligned. Whatever was mapped
+     * there before is replaced, and its second-level table freed along with the user frames it mapped.
+     */
+    pub fn map_megapage(&self, mmu: &mut MMUManager, mapping: PageMapping) -> bool {
+        if !mapping.src.is_multiple_of(MEGAPAGE_PAGES) || !mapping.dest.is_multiple_of(MEGAPAGE_PAGES) {
+            return false;
+        }
+        if !self.owns_page(mapping.src) || !self.owns_page(mapping.src + MEGAPAGE_PAGES - 1) {
+            return false;
+        }
+        if DEBUG_MAPPINGS {
+            let mut print_fifo = BasicFIFO::print_fifo();
+            let _ = writeln!(print_fifo, "Request to map megapage {:x?}", mapping);
+        }
+
+        let superpage_entry = &mut self.get_root_page_table()[mapping.src / MEGAPAGE_PAGES];
+        let was_valid = superpage_entry.v();
+        if was_valid && !VirtualMemorySpace::is_leaf(superpage_entry) {
+            let table_page = superpage_entry.ppn() as usize;
+            let table = unsafe {
+                &*((table_page * PAGE_SIZE) as *const PageTable)
+            };
+            PageAllocator::get_global(|pg| {
+                for entry in table.iter().filter(|e| e.v() && e.u()) {
+                    let _ = pg.release(entry.ppn() as usize);
+                }
+                let _ = pg.deallocate(table_page);
+            });
+        }
+        superpage_entry.0 = 0;
+        VirtualMemorySpace::set_leaf(superpage_entry, mapping.dest, &mapping, self.is_kernel());
+        mmu.share_kernel_entry(self, mapping.src / MEGAPAGE_PAGES);
+        if was_valid {
+            self.flush_tables();
+        }
+        return true;
+    }
 
+    /**
+     * Map `count` pages starting at mapping.src -> mapping.dest. Uses megapages wherever both sides
+     * are aligned and a whole one fits.
+     */
+    pub fn map_range(&self, mmu: &mut MMUManager, mapping: PageMapping, count: usize) -> bool {
+        let mut offset = 0;
+        while offset < count {
+            let current = PageMapping { src: mapping.src + offset, dest: mapping.dest + offset, ..mapping };
+            let mega = current.src.is_multiple_of(MEGAPAGE_PAGES)
+                && current.dest.is_multiple_of(MEGAPAGE_PAGES)
+                && count - offset >= MEGAPAGE_PAGES;
+            if mega {
+                if !self.map_megapage(mmu, current) {
+                    return false;
+                }
+                offset += MEGAPAGE_PAGES;
+            } else {
+                if !self.map_page(mmu, current) {
+                    return false;
+                }
+                offset += 1;
+            }
+        }
+        return true;
+    }
+
+    /** Identity map device pages, kernel-only read/write */
+    pub fn map_mmio(&self, mmu: &mut MMUManager, pages: &PageRange) -> bool {
+        return self.map_range(mmu, PageMapping {
+            src: pages.start,
+            dest: pages.start,
+            user: false,
+            read: true,
+            write: true,
+            execute: false,
+        }, pages.len());
+    }
+
+    /** Remove a page's mapping. Returns false if it wasn't mapped. */
+    pub fn unmap_page(&self, mmu: &mut MMUManager, page: usize) -> bool {
+        if !self.owns_page(page) {
+            return false;
+        }
+        let superpage = page / PAGE_TABLE_SIZE;
+        let superpage_entry = &mut self.get_root_page_table()[superpage];
+        if !superpage_entry.v() {
+            return false;
+        }
+        if VirtualMemorySpace::is_leaf(superpage_entry) && !self.split_megapage(mmu, superpage) {
+            return false;
+        }
+
+        let table_page = self.get_root_page_table()[superpage].ppn() as usize;
+        let table = unsafe {
+            &mut *((table_page * PAGE_SIZE) as *mut PageTable)
+        };
+        let entry = &mut table[page % PAGE_TABLE_SIZE];
+        if !entry.v() {
+            return false;
+        }
+        entry.0 = 0;
+
+        if table.iter().all(|e| !e.v()) {
+            // Nothing left in this table, give it back
+            self.get_root_page_table()[superpage].0 = 0;
+            mmu.share_kernel_entry(self, superpage);
+            let _ = PageAllocator::get_global(|pg| pg.deallocate(table_page));
+            self.flush_tables();
+        } else {
+            self.flush_page(page);
+        }
         return true;
     }
 
+    /** Unmap `count` pages, dropping whole megapages where the range covers them. Returns how many were mapped. */
+    pub fn unmap_range(&self, mmu: &mut MMUManager, start: usize, count: usize) -> usize {
+        let mut unmapped = 0;
+        let mut page = start;
+        while page < start + count {
+            let superpage = page / PAGE_TABLE_SIZE;
+            let superpage_entry = &mut self.get_root_page_table()[superpage];
+            if page.is_multiple_of(MEGAPAGE_PAGES) && start + count - page >= MEGAPAGE_PAGES
+                && VirtualMemorySpace::is_leaf(superpage_entry) && self.owns_page(page) {
+                superpage_entry.0 = 0;
+                mmu.share_kernel_entry(self, superpage);
+                self.flush_page(page);
+                unmapped += MEGAPAGE_PAGES;
+                page += MEGAPAGE_PAGES;
+                continue;
+            }
+            if self.unmap_page(mmu, page) {
+                unmapped += 1;
+            }
+            page += 1;
+        }
+        return unmapped;
+    }
+
+    /** Change a mapped page's permissions. Returns false if it isn't mapped. */
+    pub fn protect_page(&self, mmu: &mut MMUManager, page: usize, permissions: PagePermissions) -> bool {
+        let current = match self.lookup(page) {
+            Some(current) => current,
+            None => return false
+        };
+        if current.permissions() == permissions {
+            return true;
+        }
+        // map_page takes care of splitting megapages
+        let mapping = PageMapping {
+            src: page,
+            dest: current.dest,
+            user: permissions.user,
+            read: permissions.read,
+            write: permissions.write,
+            execute: permissions.execute,
+        };
+        return self.map_page(mmu, mapping);
+    }
+
+    /** Change permissions on every mapped page in the range. Unmapped pages are skipped. */
+    pub fn protect(&self, mmu: &mut MMUManager, pages: &PageRange, permissions: PagePermissions) {
+        let mut page = pages.start;
+        while page < pages.end {
+            let superpage_entry = &mut self.get_root_page_table()[page / PAGE_TABLE_SIZE];
+            if page.is_multiple_of(MEGAPAGE_PAGES) && pages.end - page >= MEGAPAGE_PAGES
+                && VirtualMemorySpace::is_leaf(superpage_entry) && self.owns_page(page) {
+                let dest = superpage_entry.ppn() as usize;
+                let mapping = PageMapping {
+                    src: page,
+                    dest,
+                    user: permissions.user,
+                    read: permissions.read,
+                    write: permissions.write,
+                    execute: permissions.execute,
+                };
+                VirtualMemorySpace::set_leaf(superpage_entry, dest, &mapping, self.is_kernel());
+                mmu.share_kernel_entry(self, page / MEGAPAGE_PAGES);
+                self.flush_page(page);
+                page += MEGAPAGE_PAGES;
+                continue;
+            }
+            self.protect_page(mmu, page, permissions);
+            page += 1;
+        }
+    }
+
+    /** Virtual address to physical address, and what's allowed there */
+    pub fn translate(&self, vaddr: usize) -> Option<(usize, PagePermissions)> {
+        let mapping = self.lookup(vaddr / PAGE_SIZE)?;
+        return Some((mapping.dest * PAGE_SIZE + vaddr % PAGE_SIZE, mapping.permissions()));
+    }
+
+    pub fn is_mapped(&self, page: usize) -> bool {
+        return self.lookup(page).is_some();
+    }
+
+    /** Every page in the range is mapped */
+    pub fn is_range_mapped(&self, pages: &PageRange) -> bool {
+        return (pages.start..pages.end).all(|page| self.is_mapped(page));
+    }
+
+    /** The mappings that exist in a range */
+    pub fn mappings<'a>(&'a self, pages: &PageRange) -> impl Iterator<Item=PageMapping> + 'a {
+        (pages.start..pages.end).filter_map(move |page| self.lookup(page))
+    }
+
+    /**
+     * Handle a write to a copy-on-write page: copy it if someone else still shares the frame, otherwise
+     * just take it back. Returns false if the page isn't copy-on-write (a real fault) or we're out of memory.
+     */
+    pub fn resolve_cow(&self, page: usize) -> bool {
+        if self.is_kernel() || !self.owns_page(page) {
+            return false;
+        }
+        // Cloning splits megapages, so copy-on-write pages are always in a second-level table
+        let superpage_entry = &self.get_root_page_table()[page / PAGE_TABLE_SIZE];
+        if !superpage_entry.v() || VirtualMemorySpace::is_leaf(superpage_entry) {
+            return false;
+        }
+        let table = unsafe {
+            &mut *((superpage_entry.ppn() as usize * PAGE_SIZE) as *mut PageTable)
+        };
+        let entry = &mut table[page % PAGE_TABLE_SIZE];
+        if !entry.v() || entry.rsw() & RSW_COW == 0 {
+            return false;
+        }
+
+        let frame = entry.ppn() as usize;
+        if PageAllocator::get_global(|pg| pg.ref_count(frame)) > 1 {
+            let copy = match PageAllocator::get_global(|pg| pg.allocate()) {
+                Ok(copy) => copy,
+                Err(_) => return false
+            };
+            unsafe {
+                core::ptr::copy_nonoverlapping((frame * PAGE_SIZE) as *const u8, (copy * PAGE_SIZE) as *mut u8, PAGE_SIZE);
+            }
+            entry.set_ppn(copy as u32);
+            let _ = PageAllocator::get_global(|pg| pg.release(frame));
+        }
+        entry.set_rsw(entry.rsw() & !RSW_COW);
+        entry.set_w(true);
+        // We're resolving a write, so save the hardware the trouble
+        entry.set_a(true);
+        entry.set_d(true);
+        self.flush_page(page);
+        return true;
+    }
+
+    /**
+     * Reserve a range to be backed on first touch. Returns false if it overlaps another area or isn't
+     * in the user region. Nothing is mapped until the page faults.
+     */
+    pub fn add_area(&mut self, area: VirtualMemoryArea) -> bool {
+        if self.is_kernel() || area.pages.is_empty() {
+            return false;
+        }
+        if !VirtualMemorySpace::is_user_page(area.pages.start) || !VirtualMemorySpace::is_user_page(area.pages.end - 1) {
+            return false;
+        }
+        return self.areas.get_or_insert_with(|| Box::new(AreaList::new())).insert(area);
+    }
+
+    /** Remove the area starting at `start`, and unmap whatever of it has been backed */
+    pub fn remove_area(&mut self, mmu: &mut MMUManager, start: usize) -> Option<VirtualMemoryArea> {
+        let area = self.areas.as_mut()?.remove(start)?;
+        for page in area.pages.start..area.pages.end {
+            if let Some(mapping) = self.lookup(page) {
+                self.unmap_page(mmu, page);
+                if mapping.user {
+                    let _ = PageAllocator::get_global(|pg| pg.release(mapping.dest));
+                }
+            }
+        }
+        return Some(area);
+    }
+
+    pub fn area(&self, page: usize) -> Option<&VirtualMemoryArea> {
+        return self.areas.as_ref()?.find(page);
+    }
+
+    pub fn areas(&self) -> impl Iterator<Item=&VirtualMemoryArea> {
+        self.areas.iter().flat_map(|areas| areas.iter())
+    }
+
+    /**
+     * Try to fix a page fault: copy-on-write, or backing a page in one of the areas. An Err means the
+     * access really is invalid (or we couldn't back it). `from_user` is whether U-mode made the access.
+     */
+    pub fn handle_fault(&self, mmu: &mut MMUManager, page: usize, access: FaultAccess, from_user: bool) -> Result<(), FaultError> {
+        if self.update_accessed(page, access, from_user) {
+            return Ok(());
+        }
+        if access == FaultAccess::Write && self.resolve_cow(page) {
+            return Ok(());
+        }
+        if self.is_mapped(page) {
+            // A stale TLB entry can fault on a page that's been mapped since; anything else isn't allowed
+            return match self.lookup(page) {
+                Some(mapping) if VirtualMemorySpace::mapping_allows(&mapping, access, from_user) => {
+                    self.flush_page(page);
+                    Ok(())
+                }
+                _ => Err(FaultError::Protection)
+            };
+        }
+        let area = self.area(page).ok_or(FaultError::Unmapped)?;
+        if let AreaKind::Guard = area.kind {
+            return Err(FaultError::Guard);
+        }
+        if !area.allows(access) {
+            return Err(FaultError::Protection);
+        }
+
+        let frame = PageAllocator::get_global(|pg| pg.allocate()).map_err(|_| FaultError::OutOfMemory)?;
+        let mapped = area.fill_frame(page, frame).and_then(|_| {
+            let mapping = PageMapping {
+                src: page,
+                dest: frame,
+                user: area.permissions.user,
+                read: area.permissions.read,
+                write: area.permissions.write,
+                execute: area.permissions.execute,
+            };
+            if self.map_page(mmu, mapping) { Ok(()) } else { Err(FaultError::OutOfMemory) }
+        });
+        if mapped.is_err() {
+            let _ = PageAllocator::get_global(|pg| pg.deallocate(frame));
+        }
+        return mapped;
+    }
+
+    /**
+     * For hardware that faults instead of setting A/D: if the access is allowed and only failed because A
+     * (or D, for a write) was clear, set it and return true.
+     */
+    fn update_accessed(&self, page: usize, access: FaultAccess, from_user: bool) -> bool {
+        let entry = match self.leaf_entry(page) {
+            Some(entry) => unsafe { &mut *entry },
+            None => return false
+        };
+        let mapping = VirtualMemorySpace::entry_mapping(entry, page, entry.ppn() as usize);
+        if !VirtualMemorySpace::mapping_allows(&mapping, access, from_user) {
+            return false;
+        }
+        let write = access == FaultAccess::Write;
+        if entry.a() && (!write || entry.d()) {
+            return false;
+        }
+        entry.set_a(true);
+        if write {
+            entry.set_d(true);
+        }
+        self.flush_page(page);
+        return true;
+    }
+
+    /** The valid leaf entry that maps a page: a second-level entry, or the root entry of a megapage */
+    fn leaf_entry(&self, page: usize) -> Option<*mut PageTableEntry> {
+        let superpage_entry = &mut self.get_root_page_table()[page / PAGE_TABLE_SIZE];
+        if !superpage_entry.v() {
+            return None;
+        }
+        if VirtualMemorySpace::is_leaf(superpage_entry) {
+            return Some(superpage_entry as *mut PageTableEntry);
+        }
+        let table = unsafe {
+            &mut *((superpage_entry.ppn() as usize * PAGE_SIZE) as *mut PageTable)
+        };
+        let entry = &mut table[page % PAGE_TABLE_SIZE];
+        if !entry.v() {
+            return None;
+        }
+        return Some(entry as *mut PageTableEntry);
+    }
+
+    /**
+     * Count the user region's resident, accessed and dirty pages, then clear the accessed bits so the next
+     * scan only sees pages touched in between. Dirty bits are left alone; a page stays dirty until it's
+     * written back somewhere.
+     */
+    pub fn scan_access(&mut self) -> AccessStats {
+        let mut stats = AccessStats::default();
+        if self.is_kernel() {
+            return stats;
+        }
+        for superpage_entry in self.get_root_page_table()[..KERNEL_ROOT_START].iter_mut() {
+            if !superpage_entry.v() {
+                continue;
+            }
+            if VirtualMemorySpace::is_leaf(superpage_entry) {
+                stats.count(superpage_entry, MEGAPAGE_PAGES);
+                continue;
+            }
+            let table = unsafe {
+                &mut *((superpage_entry.ppn() as usize * PAGE_SIZE) as *mut PageTable)
+            };
+            for entry in table.iter_mut().filter(|e| e.v()) {
+                stats.count(entry, 1);
+            }
+        }
+        self.flush_tables();
+        stats.scans = self.stats.scans + 1;
+        self.stats = stats;
+        return stats;
+    }
+
+    /** What the last scan_access found */
+    pub fn access_stats(&self) -> AccessStats {
+        return self.stats;
+    }
+
+    /** U-mode can only touch user pages (kernel entries are in every space, just without U) */
+    fn mapping_allows(mapping: &PageMapping, access: FaultAccess, from_user: bool) -> bool {
+        if from_user && !mapping.user {
+            return false;
+        }
+        match access {
+            FaultAccess::Read => mapping.read,
+            FaultAccess::Write => mapping.write,
+            FaultAccess::Execute => mapping.execute,
+        }
+    }
+
+    /** Replace a megapage with a second-level table of 4 KiB pages that map the same thing */
+    fn split_megapage(&self, mmu: &mut MMUManager, superpage: usize) -> bool {
+        let superpage_entry = &mut self.get_root_page_table()[superpage];
+        let page = match PageAllocator::get_global(|pg| pg.allocate()) {
+            Ok(page) => page,
+            Err(_) => return false
+        };
+        let base = superpage_entry.ppn() as usize;
+        let table = unsafe {
+            &mut *((page * PAGE_SIZE) as *mut PageTable)
+        };
+        for (i, entry) in table.iter_mut().enumerate() {
+            entry.0 = superpage_entry.0;
+            entry.set_ppn((base + i) as u32);
+        }
+
+        superpage_entry.0 = 0;
+        superpage_entry.set_ppn(page as u32);
+        superpage_entry.set_u(true); // ignored, and subpages may be user-accessible
+        superpage_entry.set_g(self.is_kernel());
+        superpage_entry.set_v(true);
+        mmu.share_kernel_entry(self, superpage);
+        self.flush_tables();
+        return true;
+    }
+
+    /** Valid with any of R/W/X set, as opposed to a pointer to the next level */
+    fn is_leaf(entry: &PageTableEntry) -> bool {
+        entry.v() && (entry.r() || entry.w() || entry.x())
+    }
+
+    /**
+     * Hardware may either set A/D itself or fault when they're clear (see handle_fault). The kernel can't
+     * take faults on its own pages, so its leaves start accessed and dirty. User leaves start accessed, and
+     * only stay dirty if they still point at the same frame.
+     */
+    fn set_leaf(entry: &mut PageTableEntry, dest: usize, mapping: &PageMapping, global: bool) {
+        let same_frame = entry.v() && entry.ppn() as usize == dest;
+        entry.set_a(true);
+        entry.set_d(global || (same_frame && entry.d()));
+        entry.set_ppn(dest as u32);
+        entry.set_g(global);
+        entry.set_x(mapping.execute);
+        entry.set_r(mapping.read);
+        entry.set_w(mapping.write);
+        entry.set_u(mapping.user);
+        entry.set_v(true);
+    }
+
+    /** Global kernel entries aren't touched by ASID-scoped fences */
+    fn flush_page(&self, page: usize) {
+        if self.is_kernel() {
+            tlb::flush_page_all_spaces(page);
+        } else {
+            tlb::flush_page(self.asid, page);
+        }
+    }
+
+    /** After changing a non-leaf entry */
+    fn flush_tables(&self) {
+        if self.is_kernel() {
+            tlb::flush_all();
+        } else {
+            tlb::flush_asid(self.asid);
+        }
+    }
+
+    fn entry_mapping(entry: &PageTableEntry, src: usize, dest: usize) -> PageMapping {
+        PageMapping {
+            src,
+            dest,
+            user: entry.u(),
+            read: entry.r(),
+            write: entry.w(),
+            execute: entry.x(),
+        }
+    }
+
+    /** Walk the page tables and find what (if anything) a virtual page maps to */
+    pub fn lookup(&self, page: usize) -> Option<PageMapping> {
+        let src_superpage = page / PAGE_TABLE_SIZE;
+        let src_childpage = page % PAGE_TABLE_SIZE;
+
+        let superpage_entry = &self.get_root_page_table()[src_superpage];
+        if !superpage_entry.v() {
+            return None;
+        }
+        if VirtualMemorySpace::is_leaf(superpage_entry) {
+            return Some(VirtualMemorySpace::entry_mapping(superpage_entry, page, superpage_entry.ppn() as usize + src_childpage));
+        }
+        let childpage_table = unsafe {
+            &*((superpage_entry.ppn() as usize * PAGE_SIZE) as *const PageTable)
+        };
+        let child_page_entry = &childpage_table[src_childpage];
+        if !child_page_entry.v() {
+            return None;
+        }
+        return Some(VirtualMemorySpace::entry_mapping(child_page_entry, page, child_page_entry.ppn() as usize));
+    }
+
+    // The table lives in its own frame, not in self, so handing out &mut from &self doesn't alias anything
+    #[allow(clippy::mut_from_ref)]
     fn get_root_page_table(&self) -> &mut PageTable {
         unsafe {
             return &mut *((self.root_page * PAGE_SIZE) as *mut PageTable);
@@ -115,18 +778,26 @@ impl VirtualMemorySpace {
     }
 }
 
+/// Slots and hardware ASIDs are separate: there can be more spaces than the hardware has ASIDs
 #[repr(C)]
 pub struct MMUManager {
     virtual_memory_spaces: *mut VirtualMemorySpace,
     kernel_id: Option<usize>,
+    /// No free slot below this
     next_id: usize,
     max_spaces: usize,
+    /// How many ASIDs satp supports, including 0 (which is always the kernel's)
+    asid_count: usize,
+    next_asid: usize,
+    /// Bumped (with a full TLB flush) every time we run out of ASIDs
+    asid_generation: usize,
 }
 
 static GLOBAL_MMU_MANAGER: RwLock<Option<MMUManager>> = RwLock::new(None);
 
 
 impl MMUManager {
+    /** Must run in machine mode, with translation off */
     pub fn create_global(memory_pages: Range<usize>) {
         let current = GLOBAL_MMU_MANAGER.upgradeable_read();
         if current.is_some() {
@@ -148,6 +819,11 @@ impl MMUManager {
             kernel_id: None,
             next_id: 0,
             max_spaces,
+            // Probed once the kernel root exists (see get_kernel); until then everything's ASID 0
+            asid_count: 1,
+            next_asid: 1,
+            // Zeroed spaces have generation 0, so they're never current
+            asid_generation: 1,
         };
 
         // Zero out the memory
@@ -158,16 +834,41 @@ impl MMUManager {
         write.replace(new);
     }
 
+    /**
+     * Don't touch user memory, or anything else that might fault, from inside `f`: resolving a page fault needs
+     * this lock too, so handle_page_fault panics rather than deadlock.
+     */
     pub fn get_global<F, T>(f: F) -> T where F: Fn(&mut MMUManager) -> T {
         let mut lock = GLOBAL_MMU_MANAGER.write();
         let pg = lock.as_mut().unwrap();
         f(pg)
     }
+
+    /** Like get_global, but gives up instead of spinning if the lock is held (e.g. when we trap with it held) */
+    pub fn try_get_global<F, T>(f: F) -> Option<T> where F: Fn(&mut MMUManager) -> T {
+        let mut lock = GLOBAL_MMU_MANAGER.try_write()?;
+        let pg = lock.as_mut()?;
+        Some(f(pg))
+    }
+
+    /**
+     * ASID is WARL: write all ones and see which stick. Bare mode requires ASID 0, so this has to use a real
+     * root table. Machine mode only, so the write doesn't change our own translation.
+     */
+    fn detect_asid_bits(root_page: usize) -> usize {
+        unsafe {
+            let previous = Satp::read();
+            Satp::sv32(usize::MAX, root_page).write();
+            let bits = Satp::read().asid().count_ones() as usize;
+            previous.write();
+            return bits;
+        }
+    }
 }
 
 impl MMUManager {
     fn get_space_raw<'a>(&self, id: usize) -> Option<&'a mut VirtualMemorySpace> {
-        if id > self.max_spaces {
+        if id >= self.max_spaces {
             return None;
         }
         unsafe {
@@ -177,25 +878,155 @@ impl MMUManager {
     }
 
     pub fn allocate_address_space(&mut self) -> Option<usize> {
-        let asid = self.next_id;
-        let space = self.get_space_raw(asid)?;
-        if space.is_initialized() {
+        let id = (self.next_id..self.max_spaces)
+            .find(|id| !self.get_space_raw(*id).unwrap().is_initialized())?;
+        let space = self.get_space_raw(id)?;
+
+        let page = PageAllocator::get_global(|pg| pg.allocate()).ok()?;
+        space.root_page = page;
+        space.asid = 0;
+        space.asid_generation = 0;
+        self.next_id = id + 1;
+
+        // Share the kernel half
+        if let Some(kernel) = self.kernel_id.and_then(|kid| self.get_space(kid)) {
+            let kernel_root = kernel.get_root_page_table();
+            let root = space.get_root_page_table();
+            for index in KERNEL_ROOT_START..PAGE_TABLE_SIZE {
+                root[index].0 = kernel_root[index].0;
+            }
+        }
+
+        return Some(id);
+    }
+
+    /**
+     * A copy of a user space that shares every frame. Writable pages become read-only and copy-on-write in
+     * both spaces, and get copied on the first write (see VirtualMemorySpace::resolve_cow).
+     */
+    pub fn clone_address_space(&mut self, id: usize) -> Option<usize> {
+        let parent = self.get_space(id)?;
+        if parent.is_kernel() {
             return None;
         }
-        // TODO: optimize?
-        let mut next = self.next_id;
-        loop {
-            next += 1;
-            if next > self.max_spaces || self.get_space_raw(next).is_none() {
+        let child_id = self.allocate_address_space()?;
+        let child = self.get_space(child_id)?;
+        let parent_root = parent.get_root_page_table();
+        let child_root = child.get_root_page_table();
+
+        let mut ok = true;
+        for index in 0..KERNEL_ROOT_START {
+            // Frames are counted per page, so share 4 KiB pages rather than megapages
+            if VirtualMemorySpace::is_leaf(&parent_root[index]) && !parent.split_megapage(self, index) {
+                ok = false;
+                break;
+            }
+            if !parent_root[index].v() {
+                continue;
+            }
+            let table_page = match PageAllocator::get_global(|pg| pg.allocate()) {
+                Ok(page) => page,
+                Err(_) => {
+                    ok = false;
+                    break;
+                }
+            };
+            child_root[index].0 = 0;
+            child_root[index].set_ppn(table_page as u32);
+            child_root[index].set_u(true); // ignored, and subpages may be user-accessible
+            child_root[index].set_v(true);
+
+            let (parent_table, child_table) = unsafe {
+                (&mut *((parent_root[index].ppn() as usize * PAGE_SIZE) as *mut PageTable),
+                 &mut *((table_page * PAGE_SIZE) as *mut PageTable))
+            };
+            for (parent_entry, child_entry) in parent_table.iter_mut().zip(child_table.iter_mut()) {
+                child_entry.0 = 0;
+                if !parent_entry.v() {
+                    continue;
+                }
+                // Only user frames belong to the space (see destroy_address_space)
+                if parent_entry.u() {
+                    let frame = parent_entry.ppn() as usize;
+                    if PageAllocator::get_global(|pg| pg.retain(frame)).is_err() {
+                        ok = false;
+                        break;
+                    }
+                    if parent_entry.w() {
+                        parent_entry.set_w(false);
+                        parent_entry.set_rsw(parent_entry.rsw() | RSW_COW);
+                    }
+                }
+                child_entry.0 = parent_entry.0;
+            }
+            if !ok {
                 break;
             }
         }
-        self.next_id = next;
 
-        let page = PageAllocator::get_global(|pg| pg.allocate())?;
-        space.root_page = page;
+        // The parent's writable pages just went read-only
+        parent.flush_tables();
+        child.areas = parent.areas.clone();
+        if !ok {
+            self.destroy_address_space(child_id);
+            return None;
+        }
+        return Some(child_id);
+    }
+
+    /**
+     * Free a space: its page tables, and its reference to every user page's frame (user pages are always
+     * backed by frames the space owns, or shares copy-on-write). The kernel's space and the active one can't be destroyed.
+     */
+    pub fn destroy_address_space(&mut self, id: usize) -> bool {
+        if Some(id) == self.kernel_id {
+            return false;
+        }
+        let space = match self.get_space(id) {
+            Some(space) => space,
+            None => return false
+        };
+        let current = Satp::read();
+        if current.mode_sv32() && current.ppn() as usize == space.root_page {
+            return false;
+        }
+
+        PageAllocator::get_global(|pg| {
+            // The kernel half is shared, and not ours to free
+            for superpage_entry in space.get_root_page_table()[..KERNEL_ROOT_START].iter() {
+                if !superpage_entry.v() {
+                    continue;
+                }
+                let ppn = superpage_entry.ppn() as usize;
+                if VirtualMemorySpace::is_leaf(superpage_entry) {
+                    if superpage_entry.u() {
+                        for frame in ppn..(ppn + MEGAPAGE_PAGES) {
+                            let _ = pg.release(frame);
+                        }
+                    }
+                    continue;
+                }
+                let table = unsafe {
+                    &*((ppn * PAGE_SIZE) as *const PageTable)
+                };
+                for entry in table.iter().filter(|e| e.v() && e.u()) {
+                    let _ = pg.release(entry.ppn() as usize);
+                }
+                let _ = pg.deallocate(ppn);
+            }
+            let _ = pg.deallocate(space.root_page);
+        });
 
-        return Some(asid);
+        if space.asid_generation == self.asid_generation {
+            tlb::flush_asid(space.asid);
+        }
+        space.areas = None;
+        space.stats = AccessStats::default();
+        space.root_page = 0;
+        space.asid = 0;
+        space.asid_generation = 0;
+        self.next_id = self.next_id.min(id);
+        return true;
     }
 
     pub fn get_space<'a>(&mut self, id: usize) -> Option<&'a mut VirtualMemorySpace> {
@@ -212,18 +1043,102 @@ impl MMUManager {
         } else {
             let kid = self.allocate_address_space().unwrap();
             self.kernel_id = Some(kid);
-            return self.get_space(kid).unwrap();
+            let kernel = self.get_space(kid).unwrap();
+            // ASID 0 is reserved for the kernel, forever
+            kernel.asid = 0;
+            kernel.asid_generation = KERNEL_GENERATION;
+            self.asid_count = 1 << MMUManager::detect_asid_bits(kernel.root_page);
+            return kernel;
+        }
+    }
+
+    /** Find the address space satp currently points at, if translation is on */
+    pub fn active_space<'a>(&mut self) -> Option<&'a mut VirtualMemorySpace> {
+        let current = Satp::read();
+        if !current.mode_sv32() {
+            return None;
+        }
+        for id in 0..self.max_spaces {
+            let space = self.get_space_raw(id)?;
+            if space.is_initialized() && space.root_page == current.ppn() as usize {
+                return Some(space);
+            }
         }
+        return None;
+    }
+
+    /** After the kernel changes one of its root entries, copy it into every other space */
+    fn share_kernel_entry(&mut self, space: &VirtualMemorySpace, index: usize) {
+        if !space.is_kernel() || index < KERNEL_ROOT_START {
+            return;
+        }
+        let entry = space.get_root_page_table()[index].0;
+        for id in 0..self.max_spaces {
+            let other = self.get_space_raw(id).unwrap();
+            if other.is_initialized() && !other.is_kernel() {
+                other.get_root_page_table()[index].0 = entry;
+            }
+        }
+    }
+
+    /** The slot id of a space this manager owns */
+    pub fn id_of(&self, space: &VirtualMemorySpace) -> usize {
+        let offset = space as *const VirtualMemorySpace as usize - self.virtual_memory_spaces as usize;
+        return offset / size_of::<VirtualMemorySpace>();
     }
 
     pub fn kernel_space_id(&self) -> usize {
         return self.kernel_id.unwrap();
     }
 
+    pub fn asid_count(&self) -> usize {
+        return self.asid_count;
+    }
+
+    /** Run scan_access over every user space. Meant to be called periodically. */
+    pub fn scan_access(&mut self) -> AccessStats {
+        let mut total = AccessStats::default();
+        for id in 0..self.max_spaces {
+            let space = self.get_space_raw(id).unwrap();
+            if !space.is_initialized() || space.is_kernel() {
+                continue;
+            }
+            let stats = space.scan_access();
+            total.resident += stats.resident;
+            total.accessed += stats.accessed;
+            total.dirty += stats.dirty;
+            total.scans = total.scans.max(stats.scans);
+        }
+        return total;
+    }
+
+    /** Give a space a current hardware ASID, if it doesn't have one. Starts a new generation when they run out. */
+    fn assign_asid(&mut self, space: &mut VirtualMemorySpace) {
+        if space.asid_generation == self.asid_generation || space.is_kernel() {
+            return;
+        }
+        if self.asid_count <= 1 {
+            // No ASIDs at all: everything shares 0, and switching flushes
+            space.asid = 0;
+            tlb::flush_all();
+            return;
+        }
+        if self.next_asid >= self.asid_count {
+            // Everyone's ASID is stale now; they'll get new ones as they're enabled
+            self.asid_generation += 1;
+            self.next_asid = 1;
+            tlb::flush_all();
+        }
+        space.asid = self.next_asid;
+        space.asid_generation = self.asid_generation;
+        self.next_asid += 1;
+    }
+
     pub fn enable(&mut self, id: usize) {
         let space = self.get_space(id).unwrap();
+        self.assign_asid(space);
         unsafe {
-            satp::set(satp::Mode::Sv32, id, space.root_page);
+            Satp::sv32(space.asid, space.root_page).write();
         }
     }
 }
@@ -231,4 +1146,4 @@ impl MMUManager {
 // Mark it as send/sync (we are careful to make sure it's safe, since we are in charge of the pointer)
 unsafe impl Sync for MMUManager {}
 
-unsafe impl Send for MMUManager {}
\ No newline at end of file
+unsafe impl Send for MMUManager {}
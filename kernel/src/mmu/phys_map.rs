@@ -0,0 +1,234 @@
+use core::fmt;
+use spin::RwLock;
+use crate::mmu::page_allocator::PageRange;
+use crate::mmu::page_tables::PAGE_SIZE;
+use crate::peripherals::MMIO_DEVICES;
+use crate::peripherals::memory_size::MemorySize;
+
+pub const RAM_BASE: usize = 0x8000_0000;
+
+/// The heap doesn't exist yet when the map is built, so it's a fixed size
+const MAX_REGIONS: usize = 16;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RegionKind {
+    /// Usable memory, unless something more specific overlaps it
+    Ram,
+    KernelImage,
+    /// RAM that's in use for something that isn't the kernel image, like the page allocator's tables
+    Reserved,
+    Mmio,
+}
+
+impl RegionKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RegionKind::Ram => "ram",
+            RegionKind::KernelImage => "kernel",
+            RegionKind::Reserved => "reserved",
+            RegionKind::Mmio => "mmio",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct MemoryRegion {
+    pub name: &'static str,
+    pub kind: RegionKind,
+    /// Byte addresses, end exclusive. Always page aligned.
+    pub start: usize,
+    pub end: usize,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+    /// False for devices; reads and writes have side effects and must not be merged or reordered
+    pub cacheable: bool,
+}
+
+impl MemoryRegion {
+    pub const fn mmio(name: &'static str, start: usize, size: usize) -> MemoryRegion {
+        MemoryRegion {
+            name,
+            kind: RegionKind::Mmio,
+            start,
+            end: start + size,
+            read: true,
+            write: true,
+            execute: false,
+            cacheable: false,
+        }
+    }
+
+    pub fn pages(&self) -> PageRange {
+        PageRange { start: self.start / PAGE_SIZE, end: self.end.div_ceil(PAGE_SIZE) }
+    }
+
+    pub fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+impl fmt::Display for MemoryRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}..{:08x} {:<8} {}{}{}{} {}",
+               self.start, self.end, self.kind.name(),
+               if self.read { 'r' } else { '-' },
+               if self.write { 'w' } else { '-' },
+               if self.execute { 'x' } else { '-' },
+               if self.cacheable { 'c' } else { '-' },
+               self.name)
+    }
+}
+
+/** Everything we know about the physical address space */
+#[derive(Clone, Default)]
+pub struct PhysMemoryMap {
+    regions: [Option<MemoryRegion>; MAX_REGIONS],
+}
+
+static GLOBAL_PHYS_MAP: RwLock<Option<PhysMemoryMap>> = RwLock::new(None);
+
+impl PhysMemoryMap {
+    /** Build the map from the peripherals' MMIO windows, MemorySize and the kernel image's pages */
+    pub fn create_global(kernel_image: PageRange) {
+        let current = GLOBAL_PHYS_MAP.upgradeable_read();
+        if current.is_some() {
+            panic!("Global physical memory map already initialized")
+        }
+        let mut write = current.upgrade();
+
+        let mut map = PhysMemoryMap { regions: [None; MAX_REGIONS] };
+        for device in MMIO_DEVICES.iter() {
+            map.add(MemoryRegion::mmio(device.name, device.address, device.size));
+        }
+        map.add(MemoryRegion {
+            name: "main memory",
+            kind: RegionKind::Ram,
+            start: RAM_BASE,
+            end: RAM_BASE + MemorySize::new().max_size(),
+            read: true,
+            write: true,
+            execute: true,
+            cacheable: true,
+        });
+        map.add(MemoryRegion {
+            name: "kernel",
+            kind: RegionKind::KernelImage,
+            start: kernel_image.start * PAGE_SIZE,
+            end: kernel_image.end * PAGE_SIZE,
+            read: true,
+            write: true,
+            execute: true,
+            cacheable: true,
+        });
+
+        write.replace(map);
+    }
+
+    pub fn get_global<F, T>(f: F) -> T where F: Fn(&mut PhysMemoryMap) -> T {
+        let mut lock = GLOBAL_PHYS_MAP.write();
+        let map = lock.as_mut().unwrap();
+        f(map)
+    }
+}
+
+impl PhysMemoryMap {
+    pub fn add(&mut self, region: MemoryRegion) {
+        match self.regions.iter_mut().find(|r| r.is_none()) {
+            Some(slot) => *slot = Some(region),
+            None => panic!("Too many memory regions, can't add {}", region)
+        }
+    }
+
+    /** Mark pages of RAM as used for something besides the kernel image */
+    pub fn reserve(&mut self, name: &'static str, pages: &PageRange) {
+        self.add(MemoryRegion {
+            name,
+            kind: RegionKind::Reserved,
+            start: pages.start * PAGE_SIZE,
+            end: pages.end * PAGE_SIZE,
+            read: true,
+            write: true,
+            execute: false,
+            cacheable: true,
+        });
+    }
+
+    /** Sorted by start address */
+    pub fn regions(&self) -> impl Iterator<Item=&MemoryRegion> {
+        let mut sorted: [Option<&MemoryRegion>; MAX_REGIONS] = [None; MAX_REGIONS];
+        for (slot, region) in sorted.iter_mut().zip(self.regions.iter().flatten()) {
+            *slot = Some(region);
+        }
+        sorted.sort_unstable_by_key(|r| r.map_or(usize::MAX, |r| r.start));
+        IntoIterator::into_iter(sorted).flatten()
+    }
+
+    pub fn of_kind(&self, kind: RegionKind) -> impl Iterator<Item=&MemoryRegion> {
+        self.regions().filter(move |r| r.kind == kind)
+    }
+
+    /** The RAM the page allocator manages. Only the first RAM region is used for now. */
+    pub fn main_memory(&self) -> Option<&MemoryRegion> {
+        self.of_kind(RegionKind::Ram).next()
+    }
+
+    /** RAM that nothing else claims */
+    pub fn is_usable_page(&self, page: usize) -> bool {
+        let start = page * PAGE_SIZE;
+        let end = start + PAGE_SIZE;
+        let mut in_ram = false;
+        for region in self.regions.iter().flatten() {
+            if !region.overlaps(start, end) {
+                continue;
+            }
+            if region.kind != RegionKind::Ram {
+                return false;
+            }
+            in_ram = true;
+        }
+        return in_ram;
+    }
+
+    /**
+     * Calls `f` with each run of usable pages in main memory, in order. Doesn't allocate, so it's safe
+     * to use before the heap exists.
+     */
+    pub fn for_each_usable<F>(&self, mut f: F) where F: FnMut(PageRange) {
+        let ram = match self.main_memory() {
+            Some(ram) => ram.pages(),
+            None => return
+        };
+        let mut cursor = ram.start;
+        while cursor < ram.end {
+            // The next thing in the way
+            let blocker = self.regions.iter().flatten()
+                .filter(|r| r.kind != RegionKind::Ram)
+                .map(|r| r.pages())
+                .filter(|p| p.end > cursor && p.start < ram.end)
+                .min_by_key(|p| p.start);
+            match blocker {
+                Some(blocked) => {
+                    if blocked.start > cursor {
+                        f(PageRange { start: cursor, end: blocked.start });
+                    }
+                    cursor = blocked.end;
+                }
+                None => {
+                    f(PageRange { start: cursor, end: ram.end });
+                    cursor = ram.end;
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for PhysMemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Physical memory map:")?;
+        for region in self.regions() {
+            writeln!(f, "  {}", region)?;
+        }
+        return Ok(());
+    }
+}
@@ -0,0 +1,43 @@
+#[cfg(not(test))]
+use core::arch::asm;
+use crate::mmu::page_tables::PAGE_SIZE;
+
+/// sfence.vma doesn't assemble for the host, and host unit tests never touch a TLB
+#[cfg(test)]
+macro_rules! asm {
+    ($($t:tt)*) => {{
+        unsafe fn host_test() -> ! { unreachable!("RISC-V assembly in a host test") }
+        host_test()
+    }}
+}
+
+// riscv 0.6's sfence_vma(asid, addr) puts the operands in the wrong registers, so these are our own
+
+/** Flush the leaf translation for one virtual page in one address space. Global mappings are left alone. */
+pub fn flush_page(asid: usize, page: usize) {
+    let addr = page * PAGE_SIZE;
+    unsafe {
+        asm!("sfence.vma {}, {}", in(reg) addr, in(reg) asid);
+    }
+}
+
+/** Flush one virtual page in every address space, including global mappings */
+pub fn flush_page_all_spaces(page: usize) {
+    let addr = page * PAGE_SIZE;
+    unsafe {
+        asm!("sfence.vma {}, zero", in(reg) addr);
+    }
+}
+
+/** Flush everything for one address space. Needed after changing a non-leaf entry. */
+pub fn flush_asid(asid: usize) {
+    unsafe {
+        asm!("sfence.vma zero, {}", in(reg) asid);
+    }
+}
+
+pub fn flush_all() {
+    unsafe {
+        asm!("sfence.vma");
+    }
+}
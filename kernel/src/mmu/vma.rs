@@ -0,0 +1,146 @@
+use alloc::vec::Vec;
+use core::fmt;
+use crate::fs::{VfsError, Whence};
+use crate::fs::fd::FileRef;
+use crate::mmu::page_allocator::PageRange;
+use crate::mmu::page_tables::{PagePermissions, PAGE_SIZE};
+
+#[derive(Clone)]
+pub enum AreaKind {
+    /// Zero-filled on first touch
+    Anonymous,
+    /// `size` bytes of `file` starting at `offset`, read in on first touch. Anything past `size` is zero.
+    File { file: FileRef, offset: u64, size: usize },
+    /// Never backed. Touching it is a fault (e.g. below a stack).
+    Guard,
+}
+
+impl fmt::Debug for AreaKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AreaKind::Anonymous => write!(f, "anonymous"),
+            AreaKind::File { offset, size, .. } => write!(f, "file {:x}+{:x}", offset, size),
+            AreaKind::Guard => write!(f, "guard"),
+        }
+    }
+}
+
+/** A range of a user space that gets backed lazily, by the page fault handler */
+#[derive(Clone, Debug)]
+pub struct VirtualMemoryArea {
+    pub pages: PageRange,
+    pub permissions: PagePermissions,
+    pub kind: AreaKind,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FaultAccess {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Clone, Debug)]
+pub enum FaultError {
+    /// No mapping and no area
+    Unmapped,
+    /// Mapped (or in an area), but not for this kind of access
+    Protection,
+    /// Hit a guard area; usually a stack overflow
+    Guard,
+    OutOfMemory,
+    Io(VfsError),
+}
+
+impl fmt::Display for FaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FaultError::Unmapped => write!(f, "not mapped"),
+            FaultError::Protection => write!(f, "protection violation"),
+            FaultError::Guard => write!(f, "guard page"),
+            FaultError::OutOfMemory => write!(f, "out of memory"),
+            FaultError::Io(e) => write!(f, "error reading backing file: {}", e),
+        }
+    }
+}
+
+impl VirtualMemoryArea {
+    pub fn allows(&self, access: FaultAccess) -> bool {
+        match access {
+            FaultAccess::Read => self.permissions.read,
+            FaultAccess::Write => self.permissions.write,
+            FaultAccess::Execute => self.permissions.execute,
+        }
+    }
+
+    /**
+     * Fill a freshly allocated (zeroed) frame with what belongs at `page`. Frames are reached by their
+     * physical address, which the kernel has identity mapped.
+     */
+    pub fn fill_frame(&self, page: usize, frame: usize) -> Result<(), FaultError> {
+        let (file, offset, size) = match &self.kind {
+            AreaKind::File { file, offset, size } => (file, *offset, *size),
+            _ => return Ok(())
+        };
+        let start = (page - self.pages.start) * PAGE_SIZE;
+        if start >= size {
+            return Ok(());
+        }
+        let len = (size - start).min(PAGE_SIZE);
+        let dest = unsafe {
+            core::slice::from_raw_parts_mut((frame * PAGE_SIZE) as *mut u8, len)
+        };
+
+        let mut file = file.lock();
+        file.seek(Whence::Set, (offset + start as u64) as i64).map_err(FaultError::Io)?;
+        let mut read = 0;
+        while read < len {
+            match file.read(&mut dest[read..]).map_err(FaultError::Io)? {
+                // Short file; the rest stays zero
+                0 => break,
+                count => read += count,
+            }
+        }
+        return Ok(());
+    }
+}
+
+/** A space's areas, sorted by start page and never overlapping */
+#[derive(Clone, Default)]
+pub struct AreaList {
+    areas: Vec<VirtualMemoryArea>
+}
+
+impl AreaList {
+    pub fn new() -> AreaList {
+        AreaList {
+            areas: Vec::new()
+        }
+    }
+
+    /** Returns false if it overlaps an existing area */
+    pub fn insert(&mut self, area: VirtualMemoryArea) -> bool {
+        let overlaps = self.areas.iter()
+            .any(|a| a.pages.start < area.pages.end && area.pages.start < a.pages.end);
+        if overlaps || area.pages.is_empty() {
+            return false;
+        }
+        let index = self.areas.iter().position(|a| a.pages.start > area.pages.start).unwrap_or(self.areas.len());
+        self.areas.insert(index, area);
+        return true;
+    }
+
+    /** Remove the area starting at `start` */
+    pub fn remove(&mut self, start: usize) -> Option<VirtualMemoryArea> {
+        let index = self.areas.iter().position(|a| a.pages.start == start)?;
+        return Some(self.areas.remove(index));
+    }
+
+    pub fn find(&self, page: usize) -> Option<&VirtualMemoryArea> {
+        self.areas.iter().find(|a| a.pages.contains(page))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&VirtualMemoryArea> {
+        self.areas.iter()
+    }
+}
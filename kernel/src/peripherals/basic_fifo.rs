@@ -2,6 +2,10 @@ use volatile_register::{RW, RO};
 use core::fmt;
 use crate::peripherals::stream::{InStream, OutStream};
 
+pub const PRINT_FIFO_ADDRESS: usize = 0x1000_0000;
+pub const COMPONENT_FIFO_ADDRESS: usize = 0x1000_1000;
+pub const PANIC_FIFO_ADDRESS: usize = 0x1000_2000;
+
 pub struct BasicFIFO {
     p: &'static mut BasicFIFORegisters
 }
@@ -15,21 +19,26 @@ struct BasicFIFORegisters {
 
 impl BasicFIFO {
     pub fn component_fifo() -> BasicFIFO {
-        return BasicFIFO::new(0x1000_1000)
+        return BasicFIFO::new(COMPONENT_FIFO_ADDRESS)
     }
     pub fn panic_fifo() -> BasicFIFO {
-        return BasicFIFO::new(0x1000_2000)
+        return BasicFIFO::new(PANIC_FIFO_ADDRESS)
     }
     pub fn print_fifo() -> BasicFIFO {
-        return BasicFIFO::new(0x1000_0000)
+        return BasicFIFO::new(PRINT_FIFO_ADDRESS)
     }
 
-    fn new(addr: u32) -> BasicFIFO {
+    fn new(addr: usize) -> BasicFIFO {
         BasicFIFO {
             p: unsafe { &mut *(addr as *mut BasicFIFORegisters) }
         }
     }
 
+    /** Whether there's anything to read */
+    pub fn read_ready(&self) -> bool {
+        return self.p.read_ready.read() != 0;
+    }
+
     pub fn write_ready(&mut self) {
         unsafe {
             self.p.write_ready.write(1);
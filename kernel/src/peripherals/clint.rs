@@ -0,0 +1,59 @@
+use volatile_register::RW;
+
+/// The usual SiFive/QEMU layout. Machine mode only: it isn't in MMIO_DEVICES, so S-mode never maps it.
+pub const CLINT_ADDRESS: usize = 0x0200_0000;
+const MTIMECMP_OFFSET: usize = 0x4000;
+const MTIME_OFFSET: usize = 0xBFF8;
+
+pub struct Clint {
+    mtimecmp: &'static mut Time64,
+    mtime: &'static mut Time64,
+}
+
+/// A 64 bit timer register, as two halves
+#[repr(C)]
+struct Time64 {
+    pub low: RW<u32>,
+    pub high: RW<u32>,
+}
+
+impl Clint {
+    /** Hart 0's registers */
+    pub fn new() -> Clint {
+        Clint {
+            mtimecmp: unsafe { &mut *((CLINT_ADDRESS + MTIMECMP_OFFSET) as *mut Time64) },
+            mtime: unsafe { &mut *((CLINT_ADDRESS + MTIME_OFFSET) as *mut Time64) },
+        }
+    }
+
+    /** A single load of mtime's low half. For probing: if the load faults, mtime's retry loop would spin on garbage. */
+    pub fn mtime_low(&self) -> u32 {
+        return self.mtime.low.read();
+    }
+
+    pub fn mtime(&self) -> u64 {
+        // Re-read if the low half wrapped between reads
+        loop {
+            let high = self.mtime.high.read();
+            let low = self.mtime.low.read();
+            if self.mtime.high.read() == high {
+                return ((high as u64) << 32) | low as u64;
+            }
+        }
+    }
+
+    pub fn set_mtimecmp(&mut self, value: u64) {
+        unsafe {
+            // Never let the compare be briefly lower than both the old and new values
+            self.mtimecmp.low.write(u32::MAX);
+            self.mtimecmp.high.write((value >> 32) as u32);
+            self.mtimecmp.low.write(value as u32);
+        }
+    }
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Clint::new()
+    }
+}
@@ -0,0 +1,48 @@
+use alloc::string::String;
+use volatile_register::RO;
+
+/// The EEPROM's code; we only ever use its data
+pub const EEPROM_ADDRESS: usize = 0x2000_0000;
+pub const EEPROM_DATA_ADDRESS: usize = 0x2001_0000;
+
+/// Matches UUID_LEN in the EEPROM: a 36 char UUID plus room for the terminator
+pub const BOOT_ADDRESS_LEN: usize = 38;
+
+pub struct Eeprom {
+    p: &'static mut EepromDataRegisters
+}
+
+#[repr(C)]
+struct EepromDataRegisters {
+    pub boot_address: [RO<u8>; BOOT_ADDRESS_LEN]
+}
+
+impl Eeprom {
+    pub fn new() -> Eeprom {
+        Eeprom {
+            p: unsafe { &mut *(EEPROM_DATA_ADDRESS as *mut EepromDataRegisters) }
+        }
+    }
+
+    /** The filesystem the EEPROM booted us from, if one is set */
+    pub fn boot_address(&self) -> Option<String> {
+        let mut address = String::new();
+        for byte in self.p.boot_address.iter() {
+            let c = byte.read();
+            if c == 0 {
+                break;
+            }
+            address.push(c as char);
+        }
+        if address.is_empty() {
+            return None;
+        }
+        return Some(address);
+    }
+}
+
+impl Default for Eeprom {
+    fn default() -> Self {
+        Eeprom::new()
+    }
+}
@@ -1,5 +1,7 @@
 use volatile_register::RO;
 
+pub const MEMORY_SIZE_ADDRESS: usize = 0x7FFF_0000;
+
 pub struct MemorySize {
     p: &'static mut MemorySizeRegisters
 }
@@ -12,11 +14,17 @@ struct MemorySizeRegisters {
 impl MemorySize {
     pub fn new() -> MemorySize {
         MemorySize {
-            p: unsafe { &mut *(0x7FFF_0000 as *mut MemorySizeRegisters) }
+            p: unsafe { &mut *(MEMORY_SIZE_ADDRESS as *mut MemorySizeRegisters) }
         }
     }
 
     pub fn max_size(&self) -> usize {
         self.p.size.read()
     }
-}
\ No newline at end of file
+}
+
+impl Default for MemorySize {
+    fn default() -> Self {
+        MemorySize::new()
+    }
+}
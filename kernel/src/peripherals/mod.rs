@@ -1,4 +1,23 @@
 pub mod memory_size;
 pub mod stream;
 pub mod taggedbinary;
-pub mod basic_fifo;
\ No newline at end of file
+pub mod basic_fifo;
+pub mod eeprom;
+pub mod clint;
+use crate::mmu::page_tables::PAGE_SIZE;
+
+pub struct MmioDevice {
+    pub name: &'static str,
+    pub address: usize,
+    pub size: usize,
+}
+
+/// Every memory mapped device we talk to. These need to be mapped before we leave machine mode.
+pub const MMIO_DEVICES: [MmioDevice; 6] = [
+    MmioDevice { name: "print fifo", address: basic_fifo::PRINT_FIFO_ADDRESS, size: PAGE_SIZE },
+    MmioDevice { name: "component fifo", address: basic_fifo::COMPONENT_FIFO_ADDRESS, size: PAGE_SIZE },
+    MmioDevice { name: "panic fifo", address: basic_fifo::PANIC_FIFO_ADDRESS, size: PAGE_SIZE },
+    MmioDevice { name: "eeprom", address: eeprom::EEPROM_ADDRESS, size: PAGE_SIZE },
+    MmioDevice { name: "eeprom data", address: eeprom::EEPROM_DATA_ADDRESS, size: PAGE_SIZE },
+    MmioDevice { name: "memory size", address: memory_size::MEMORY_SIZE_ADDRESS, size: PAGE_SIZE },
+];
@@ -7,17 +7,17 @@ pub trait OutStream {
         self.write(v)
     }
     fn write16(&mut self, v: u16) {
-        self.write((v >> 0) as u8);
+        self.write(v as u8);
         self.write((v >> 8) as u8);
     }
     fn write32(&mut self, v: u32) {
-        self.write((v >> 0) as u8);
+        self.write(v as u8);
         self.write((v >> 8) as u8);
         self.write((v >> 16) as u8);
         self.write((v >> 24) as u8);
     }
     fn write64(&mut self, v: u64) {
-        self.write((v >> 0) as u8);
+        self.write(v as u8);
         self.write((v >> 8) as u8);
         self.write((v >> 16) as u8);
         self.write((v >> 24) as u8);
@@ -27,7 +27,7 @@ pub trait OutStream {
         self.write((v >> 56) as u8);
     }
     fn write128(&mut self, v: u128) {
-        self.write((v >> 0) as u8);
+        self.write(v as u8);
         self.write((v >> 8) as u8);
         self.write((v >> 16) as u8);
         self.write((v >> 24) as u8);
@@ -45,8 +45,8 @@ pub trait OutStream {
         self.write((v >> 120) as u8);
     }
     fn write_bytes(&mut self, arr: &[u8]) {
-        for i in 0..arr.len() {
-            self.write(arr[i]);
+        for &b in arr {
+            self.write(b);
         }
     }
 }
@@ -98,8 +98,8 @@ pub trait InStream {
 
     fn read_bytes(&mut self, len: usize, arr: &mut [u8]) {
         let count = min(len, arr.len());
-        for i in 0..count {
-            arr[i] = self.read();
+        for b in arr.iter_mut().take(count) {
+            *b = self.read();
         }
         // Always read len; ignore extras
         for i in count..len {
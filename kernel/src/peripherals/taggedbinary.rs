@@ -1,79 +1,358 @@
+use alloc::borrow::Cow;
+use alloc::vec::Vec;
+use core::str;
+use crate::drivers::remote_value::RemoteValue;
 use crate::peripherals::stream::{InStream, OutStream};
 
+pub const BI_NULL: u8 = 0x00;
+pub const BI_INT8: u8 = 0x01;
+pub const BI_INT16: u8 = 0x02;
+pub const BI_INT32: u8 = 0x03;
+pub const BI_INT64: u8 = 0x04;
+pub const BI_INT128: u8 = 0x05;
+pub const BI_BYTES: u8 = 0x06;
+pub const BI_OBJECT: u8 = 0x07;
+pub const BI_VALUE: u8 = 0x08;
+pub const BI_END: u8 = 0xFF;
 
-#[derive(Copy, Clone)]
-enum TaggedBinary {
+/// Objects nested deeper than this are rejected rather than blowing the kernel stack
+pub const MAX_OBJECT_DEPTH: usize = 16;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TaggedBinary<'a> {
     NULL,
     Int8(u8),
     Int16(u16),
     Int32(u32),
     Int64(u64),
     Int128(u128),
-    BytesStatic([u8; 256]),
-    //TODO: use vec<>! Need kmalloc first
+    /// Borrowed when we're writing, owned when read off a stream
+    Bytes(Cow<'a, [u8]>),
+    /// Key/value pairs; arrays are objects keyed by index
+    Object(Vec<(TaggedBinary<'a>, TaggedBinary<'a>)>),
+    /// Raw value id, as read off the wire
     Value(u32),
+    /// Owned value; written as a Value. ComponentClient turns results into these.
+    Handle(RemoteValue),
     END,
 }
 
-impl TaggedBinary {
-    fn read_from(input: &mut dyn InStream) -> TaggedBinary {
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DecodeError {
+    UnknownType(u8),
+    /// An object had a key with no value before its END
+    MissingValue,
+    TooDeep,
+    /// Byte string length we couldn't allocate room for
+    TooLong(usize),
+}
+
+impl TaggedBinary<'_> {
+    pub fn read_from(input: &mut dyn InStream) -> Result<TaggedBinary<'static>, DecodeError> {
+        return TaggedBinary::read_nested(input, 0);
+    }
+
+    fn read_nested(input: &mut dyn InStream, depth: usize) -> Result<TaggedBinary<'static>, DecodeError> {
         let t = input.read();
-        return match t {
-            0x00 => { TaggedBinary::NULL }
-            0x01 => { TaggedBinary::Int8(input.read8()) }
-            0x02 => { TaggedBinary::Int16(input.read16()) }
-            0x03 => { TaggedBinary::Int32(input.read32()) }
-            0x04 => { TaggedBinary::Int64(input.read64()) }
-            0x05 => { TaggedBinary::Int128(input.read128()) }
-            0x06 => {
+        return Ok(match t {
+            BI_NULL => { TaggedBinary::NULL }
+            BI_INT8 => { TaggedBinary::Int8(input.read8()) }
+            BI_INT16 => { TaggedBinary::Int16(input.read16()) }
+            BI_INT32 => { TaggedBinary::Int32(input.read32()) }
+            BI_INT64 => { TaggedBinary::Int64(input.read64()) }
+            BI_INT128 => { TaggedBinary::Int128(input.read128()) }
+            BI_BYTES => {
+                // The length comes from the host, so a bad one has to fail the decode, not the allocation
                 let len = input.read32() as usize;
-                let mut arr: [u8; 256] = [0; 256];
+                let mut arr = Vec::new();
+                if arr.try_reserve_exact(len).is_err() {
+                    return Err(DecodeError::TooLong(len));
+                }
+                arr.resize(len, 0);
                 input.read_bytes(len, &mut arr);
-                TaggedBinary::BytesStatic(arr)
+                TaggedBinary::Bytes(Cow::Owned(arr))
             }
-            0x08 => { TaggedBinary::Value(input.read32()) }
-            0xFF => { TaggedBinary::END }
-            _ => panic!("Unknown tagged binary type {}", t)
-        };
+            BI_OBJECT => {
+                if depth >= MAX_OBJECT_DEPTH {
+                    return Err(DecodeError::TooDeep);
+                }
+                let mut entries = Vec::new();
+                loop {
+                    let key = TaggedBinary::read_nested(input, depth + 1)?;
+                    if key == TaggedBinary::END {
+                        break;
+                    }
+                    let value = TaggedBinary::read_nested(input, depth + 1)?;
+                    if value == TaggedBinary::END {
+                        return Err(DecodeError::MissingValue);
+                    }
+                    entries.push((key, value));
+                }
+                TaggedBinary::Object(entries)
+            }
+            BI_VALUE => { TaggedBinary::Value(input.read32()) }
+            BI_END => { TaggedBinary::END }
+            _ => return Err(DecodeError::UnknownType(t))
+        });
     }
 
-    fn write_to(&self, output: &mut dyn OutStream) {
+    pub fn write_to(&self, output: &mut dyn OutStream) {
         match self {
             TaggedBinary::NULL => {
-                output.write8(0x00);
+                output.write8(BI_NULL);
             },
             TaggedBinary::Int8(v) => {
-                output.write8(0x01);
+                output.write8(BI_INT8);
                 output.write8(*v);
             },
             TaggedBinary::Int16(v) => {
-                output.write8(0x02);
+                output.write8(BI_INT16);
                 output.write16(*v);
             },
             TaggedBinary::Int32(v) => {
-                output.write8(0x03);
+                output.write8(BI_INT32);
                 output.write32(*v);
             },
             TaggedBinary::Int64(v) => {
-                output.write8(0x04);
+                output.write8(BI_INT64);
                 output.write64(*v);
             },
             TaggedBinary::Int128(v) => {
-                output.write8(0x05);
+                output.write8(BI_INT128);
                 output.write128(*v);
             },
-            TaggedBinary::BytesStatic(v) => {
-                output.write8(0x06);
+            TaggedBinary::Bytes(v) => {
+                output.write8(BI_BYTES);
                 output.write32(v.len() as u32);
                 output.write_bytes(v);
             },
+            TaggedBinary::Object(entries) => {
+                output.write8(BI_OBJECT);
+                for (key, value) in entries {
+                    key.write_to(output);
+                    value.write_to(output);
+                }
+                output.write8(BI_END);
+            },
             TaggedBinary::Value(v) => {
-                output.write8(0x08);
+                output.write8(BI_VALUE);
                 output.write32(*v);
             },
+            TaggedBinary::Handle(v) => {
+                output.write8(BI_VALUE);
+                output.write32(v.id());
+            },
             TaggedBinary::END => {
-                output.write8(0xFF);
+                output.write8(BI_END);
             },
         }
     }
-}
\ No newline at end of file
+}
+
+impl<'a> TaggedBinary<'a> {
+    pub fn string(s: &'a str) -> TaggedBinary<'a> {
+        TaggedBinary::Bytes(Cow::Borrowed(s.as_bytes()))
+    }
+
+    pub fn bytes(b: &'a [u8]) -> TaggedBinary<'a> {
+        TaggedBinary::Bytes(Cow::Borrowed(b))
+    }
+
+    /** Build an array-style object, keyed 1..n like the host's tables */
+    pub fn array(values: Vec<TaggedBinary<'a>>) -> TaggedBinary<'a> {
+        TaggedBinary::Object(values.into_iter()
+            .enumerate()
+            .map(|(i, v)| (TaggedBinary::Int32(i as u32 + 1), v))
+            .collect())
+    }
+
+    pub fn into_owned(self) -> TaggedBinary<'static> {
+        match self {
+            TaggedBinary::NULL => TaggedBinary::NULL,
+            TaggedBinary::Int8(v) => TaggedBinary::Int8(v),
+            TaggedBinary::Int16(v) => TaggedBinary::Int16(v),
+            TaggedBinary::Int32(v) => TaggedBinary::Int32(v),
+            TaggedBinary::Int64(v) => TaggedBinary::Int64(v),
+            TaggedBinary::Int128(v) => TaggedBinary::Int128(v),
+            TaggedBinary::Bytes(v) => TaggedBinary::Bytes(Cow::Owned(v.into_owned())),
+            TaggedBinary::Object(entries) => TaggedBinary::Object(entries.into_iter()
+                .map(|(k, v)| (k.into_owned(), v.into_owned()))
+                .collect()),
+            TaggedBinary::Value(v) => TaggedBinary::Value(v),
+            TaggedBinary::Handle(v) => TaggedBinary::Handle(v),
+            TaggedBinary::END => TaggedBinary::END,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            TaggedBinary::Bytes(v) => Some(v),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        str::from_utf8(self.as_bytes()?).ok()
+    }
+
+    /** Any of the integer types up to 64 bits, widened */
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            TaggedBinary::Int8(v) => Some(*v as u64),
+            TaggedBinary::Int16(v) => Some(*v as u64),
+            TaggedBinary::Int32(v) => Some(*v as u64),
+            TaggedBinary::Int64(v) => Some(*v),
+            _ => None
+        }
+    }
+
+    pub fn as_handle(&self) -> Option<&RemoteValue> {
+        match self {
+            TaggedBinary::Handle(v) => Some(v),
+            _ => None
+        }
+    }
+
+    /** Take ownership of any raw Value ids, including ones nested in objects */
+    pub fn adopt_values(self) -> TaggedBinary<'a> {
+        match self {
+            TaggedBinary::Value(id) => TaggedBinary::Handle(RemoteValue::adopt(id)),
+            TaggedBinary::Object(entries) => TaggedBinary::Object(entries.into_iter()
+                .map(|(k, v)| (k.adopt_values(), v.adopt_values()))
+                .collect()),
+            other => other
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&[(TaggedBinary<'a>, TaggedBinary<'a>)]> {
+        match self {
+            TaggedBinary::Object(entries) => Some(entries),
+            _ => None
+        }
+    }
+
+    /** Look up a string key in an object */
+    pub fn get(&self, key: &str) -> Option<&TaggedBinary<'a>> {
+        self.as_object()?
+            .iter()
+            .find(|(k, _)| k.as_bytes() == Some(key.as_bytes()))
+            .map(|(_, v)| v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write into it, then read the same bytes back
+    struct Buffer {
+        data: Vec<u8>,
+        position: usize,
+    }
+
+    impl Buffer {
+        fn new() -> Buffer {
+            Buffer { data: Vec::new(), position: 0 }
+        }
+    }
+
+    impl OutStream for Buffer {
+        fn write(&mut self, v: u8) {
+            self.data.push(v);
+        }
+    }
+
+    impl InStream for Buffer {
+        fn read(&mut self) -> u8 {
+            let v = self.data[self.position];
+            self.position += 1;
+            v
+        }
+    }
+
+    fn round_trip(value: &TaggedBinary) -> TaggedBinary<'static> {
+        let mut buffer = Buffer::new();
+        value.write_to(&mut buffer);
+        let decoded = TaggedBinary::read_from(&mut buffer).unwrap();
+        assert_eq!(buffer.position, buffer.data.len(), "didn't read everything back");
+        decoded
+    }
+
+    #[test]
+    fn scalars_round_trip() {
+        let values = [
+            TaggedBinary::NULL,
+            TaggedBinary::Int8(0xAB),
+            TaggedBinary::Int16(0xABCD),
+            TaggedBinary::Int32(0xDEAD_BEEF),
+            TaggedBinary::Int64(0x0123_4567_89AB_CDEF),
+            TaggedBinary::Int128(u128::MAX - 1),
+            TaggedBinary::Value(42),
+            TaggedBinary::END,
+        ];
+        for value in values.iter() {
+            assert_eq!(&round_trip(value), value);
+        }
+    }
+
+    #[test]
+    fn bytes_round_trip_owned() {
+        let decoded = round_trip(&TaggedBinary::string("hello"));
+        assert_eq!(decoded.as_str(), Some("hello"));
+        assert!(matches!(decoded, TaggedBinary::Bytes(Cow::Owned(_))));
+        assert_eq!(round_trip(&TaggedBinary::bytes(&[])).as_bytes(), Some(&[][..]));
+    }
+
+    #[test]
+    fn nested_objects_round_trip() {
+        let value = TaggedBinary::Object(vec![
+            (TaggedBinary::string("name"), TaggedBinary::string("disk")),
+            (TaggedBinary::string("list"), TaggedBinary::array(vec![TaggedBinary::Int8(1), TaggedBinary::NULL])),
+            (TaggedBinary::string("empty"), TaggedBinary::Object(Vec::new())),
+        ]);
+        let decoded = round_trip(&value);
+        assert_eq!(decoded, value);
+        assert_eq!(decoded.get("name").and_then(|v| v.as_str()), Some("disk"));
+        assert!(decoded.get("missing").is_none());
+    }
+
+    #[test]
+    fn arrays_are_keyed_from_one() {
+        let array = TaggedBinary::array(vec![TaggedBinary::Int8(7), TaggedBinary::Int8(8)]);
+        let keys: Vec<u64> = array.as_object().unwrap().iter().map(|(k, _)| k.as_u64().unwrap()).collect();
+        assert_eq!(keys, vec![1, 2]);
+    }
+
+    #[test]
+    fn unknown_type_is_an_error() {
+        let mut buffer = Buffer::new();
+        buffer.write(0x42);
+        assert_eq!(TaggedBinary::read_from(&mut buffer), Err(DecodeError::UnknownType(0x42)));
+    }
+
+    #[test]
+    fn key_without_value_is_an_error() {
+        let mut buffer = Buffer::new();
+        buffer.write(BI_OBJECT);
+        TaggedBinary::string("key").write_to(&mut buffer);
+        buffer.write(BI_END);
+        assert_eq!(TaggedBinary::read_from(&mut buffer), Err(DecodeError::MissingValue));
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        let mut value = TaggedBinary::NULL;
+        for _ in 0..=MAX_OBJECT_DEPTH {
+            value = TaggedBinary::array(vec![value]);
+        }
+        let mut buffer = Buffer::new();
+        value.write_to(&mut buffer);
+        assert_eq!(TaggedBinary::read_from(&mut buffer), Err(DecodeError::TooDeep));
+
+        let mut shallow = TaggedBinary::NULL;
+        for _ in 0..MAX_OBJECT_DEPTH {
+            shallow = TaggedBinary::array(vec![shallow]);
+        }
+        assert_eq!(round_trip(&shallow), shallow);
+    }
+}
@@ -0,0 +1,76 @@
+pub mod scheduler;
+pub mod syscall;
+
+use core::mem::size_of;
+use crate::fs::fd::FileDescriptorTable;
+use crate::loader::{self, LoadError};
+use crate::mmu::page_tables::{MMUManager, PAGE_SIZE};
+use crate::process::scheduler::Scheduler;
+use crate::trap::frame::TrapFrame;
+
+pub type Pid = usize;
+
+/// Each process gets 2^order pages of kernel stack. Its saved registers sit at the top.
+pub const KERNEL_STACK_ORDER: usize = 1;
+pub const KERNEL_STACK_SIZE: usize = PAGE_SIZE << KERNEL_STACK_ORDER;
+
+/// Exit code for a process killed by the kernel (e.g. an unhandled page fault)
+pub const KILLED_EXIT_CODE: i32 = -1;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ProcessState {
+    /// In the ready queue
+    Ready,
+    Running,
+    /// Until timer::ticks() reaches this
+    Sleeping(u64),
+    /// For a child to exit: a specific one, or any
+    Waiting(Option<Pid>),
+    /// Exited with this code, and not waited for yet. Its memory is already gone.
+    Zombie(i32),
+}
+
+/** Process control block */
+pub struct Process {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    /// MMUManager space id. None once the process has exited.
+    pub space: Option<usize>,
+    /// First page of the kernel stack. None once the process has exited.
+    kernel_stack: Option<usize>,
+    pub state: ProcessState,
+    pub files: FileDescriptorTable,
+}
+
+impl Process {
+    pub fn kernel_stack_top(&self) -> Option<usize> {
+        return self.kernel_stack.map(|page| page * PAGE_SIZE + KERNEL_STACK_SIZE);
+    }
+
+    /**
+     * Saved user registers. The trap vector saves them at the top of the kernel stack (sscratch points
+     * there while in user mode), so this is also where they get restored from.
+     */
+    pub fn frame(&mut self) -> Option<&mut TrapFrame> {
+        let top = self.kernel_stack_top()?;
+        unsafe {
+            return Some(&mut *((top - size_of::<TrapFrame>()) as *mut TrapFrame));
+        }
+    }
+
+    pub fn is_alive(&self) -> bool {
+        !matches!(self.state, ProcessState::Zombie(_))
+    }
+}
+
+/** Load an executable and queue it to run. Only its parent (if any) can wait for it. */
+pub fn spawn_path(path: &str, argv: &[&str], envp: &[&str], parent: Option<Pid>) -> Result<Pid, LoadError> {
+    let program = loader::load_path(path, argv, envp)?;
+    match Scheduler::get_global(|scheduler| scheduler.spawn(&program, parent)) {
+        Ok(pid) => Ok(pid),
+        Err(_) => {
+            MMUManager::get_global(|mmu| mmu.destroy_address_space(program.space));
+            Err(LoadError::OutOfMemory)
+        }
+    }
+}
@@ -0,0 +1,377 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::mem::size_of;
+use spin::RwLock;
+use crate::fs::fd::FileDescriptorTable;
+use crate::loader::LoadedProgram;
+use crate::mmu::page_allocator::{PageAllocError, PageAllocator};
+use crate::mmu::page_tables::{MMUManager, PAGE_SIZE};
+use crate::timer;
+use crate::timer::wheel::{TimerAction, TimerWheel};
+use crate::process::{Pid, Process, ProcessState, KERNEL_STACK_ORDER, KERNEL_STACK_SIZE};
+use crate::trap::frame::TrapFrame;
+
+/// Timer ticks a process runs before the next one in line gets a turn
+const QUANTUM_TICKS: u64 = 1;
+
+/// sstatus bits for the idle frame: come back to supervisor mode, with interrupts on
+const SSTATUS_SPP: usize = 1 << 8;
+const SSTATUS_SPIE: usize = 1 << 5;
+
+extern "C" {
+    /// Restore a TrapFrame and sret into it (the tail of the supervisor trap vector)
+    fn _supervisor_resume(frame: *mut TrapFrame) -> !;
+}
+
+/** What a finished wait hands back: the child, and its exit code */
+pub type WaitResult = (Pid, i32);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WaitError {
+    /// Nothing to wait for
+    NoChild,
+    /// Not finished yet; the caller has been put to sleep until it is
+    Blocked,
+}
+
+/**
+ * Preemptive round robin. Nothing switches in the middle of the kernel: handlers (syscalls, the timer, faults)
+ * only change process states and ask for a reschedule, and the actual switch happens on the way out of the
+ * trap, by resuming a different process's saved frame.
+ */
+pub struct Scheduler {
+    processes: BTreeMap<Pid, Process>,
+    ready: VecDeque<Pid>,
+    current: Option<Pid>,
+    next_pid: Pid,
+    /// Tick the current process started running at
+    slice_start: u64,
+    need_resched: bool,
+    /// Where idle runs when nothing else can
+    idle_stack: usize,
+    /// Spaces and kernel stacks of exited processes. Can't be freed until we're off them.
+    retired_spaces: Vec<usize>,
+    retired_stacks: Vec<usize>,
+}
+
+static GLOBAL_SCHEDULER: RwLock<Option<Scheduler>> = RwLock::new(None);
+
+impl Scheduler {
+    /** Needs the page allocator and heap */
+    pub fn create_global() {
+        let current = GLOBAL_SCHEDULER.upgradeable_read();
+        if current.is_some() {
+            panic!("Global scheduler already initialized")
+        }
+        let mut write = current.upgrade();
+
+        let idle_stack = match PageAllocator::get_global(|pg| pg.allocate_order(KERNEL_STACK_ORDER)) {
+            Ok(page) => page,
+            Err(e) => panic!("Unable to allocate the idle stack: {}", e)
+        };
+        write.replace(Scheduler {
+            processes: BTreeMap::new(),
+            ready: VecDeque::new(),
+            current: None,
+            next_pid: 1,
+            slice_start: 0,
+            need_resched: false,
+            idle_stack,
+            retired_spaces: Vec::new(),
+            retired_stacks: Vec::new(),
+        });
+    }
+
+    pub fn get_global<F, T>(f: F) -> T where F: Fn(&mut Scheduler) -> T {
+        let mut lock = GLOBAL_SCHEDULER.write();
+        let scheduler = lock.as_mut().unwrap();
+        f(scheduler)
+    }
+
+    /** None if there's no scheduler yet, or it's in use */
+    pub fn try_get_global<F, T>(f: F) -> Option<T> where F: Fn(&mut Scheduler) -> T {
+        let mut lock = GLOBAL_SCHEDULER.try_write()?;
+        let scheduler = lock.as_mut()?;
+        Some(f(scheduler))
+    }
+}
+
+/** Start running processes. Never returns; the boot stack is abandoned. */
+pub fn run() -> ! {
+    let frame = Scheduler::get_global(|scheduler| scheduler.start());
+    unsafe {
+        _supervisor_resume(frame);
+    }
+}
+
+/** Nothing to run: wait for an interrupt to make something ready */
+extern "C" fn idle() -> ! {
+    loop {
+        unsafe { riscv::asm::wfi(); }
+    }
+}
+
+impl Scheduler {
+    /** Queue a loaded program as a new process */
+    pub fn spawn(&mut self, program: &LoadedProgram, parent: Option<Pid>) -> Result<Pid, PageAllocError> {
+        let kernel_stack = PageAllocator::get_global(|pg| pg.allocate_order(KERNEL_STACK_ORDER))?;
+        let pid = self.next_pid;
+        self.next_pid += 1;
+
+        let mut process = Process {
+            pid,
+            parent,
+            space: Some(program.space),
+            kernel_stack: Some(kernel_stack),
+            state: ProcessState::Ready,
+            files: FileDescriptorTable::new(),
+        };
+        *process.frame().unwrap() = program.initial_frame();
+        self.processes.insert(pid, process);
+        self.ready.push_back(pid);
+        return Ok(pid);
+    }
+
+    pub fn current(&self) -> Option<Pid> {
+        return self.current;
+    }
+
+    pub fn current_process(&mut self) -> Option<&mut Process> {
+        let pid = self.current?;
+        return self.processes.get_mut(&pid);
+    }
+
+    pub fn process(&self, pid: Pid) -> Option<&Process> {
+        return self.processes.get(&pid);
+    }
+
+    /** Timer interrupt: preempt whoever's used up their slice */
+    pub fn tick(&mut self, tick: u64) {
+        if tick - self.slice_start >= QUANTUM_TICKS {
+            self.need_resched = true;
+        }
+    }
+
+    /** A sleeper's timer went off. Anyone else (e.g. it's exited since) is left alone. */
+    pub fn wake(&mut self, pid: Pid) {
+        let process = match self.processes.get_mut(&pid) {
+            Some(process) => process,
+            None => return
+        };
+        if let ProcessState::Sleeping(_) = process.state {
+            process.state = ProcessState::Ready;
+            self.ready.push_back(pid);
+            self.need_resched = true;
+        }
+    }
+
+    /** Give up the rest of this slice */
+    pub fn yield_current(&mut self) {
+        self.need_resched = true;
+    }
+
+    /** Sleep for at least `ticks` timer ticks */
+    pub fn sleep_current(&mut self, ticks: u64) {
+        let until = timer::ticks() + ticks.max(1);
+        if let Some(process) = self.current_process() {
+            process.state = ProcessState::Sleeping(until);
+            let pid = process.pid;
+            TimerWheel::get_global(|wheel| wheel.add(until, TimerAction::Wake(pid)));
+        }
+        self.need_resched = true;
+    }
+
+    /**
+     * End the current process. Its memory goes right away; the PCB stays as a zombie until the parent
+     * waits for it (or goes immediately, if there's no parent).
+     */
+    pub fn exit_current(&mut self, code: i32) {
+        let pid = match self.current {
+            Some(pid) => pid,
+            None => return
+        };
+        self.need_resched = true;
+
+        let parent = {
+            let process = self.processes.get_mut(&pid).unwrap();
+            process.state = ProcessState::Zombie(code);
+            process.files.close_all();
+            if let Some(space) = process.space.take() {
+                self.retired_spaces.push(space);
+            }
+            if let Some(stack) = process.kernel_stack.take() {
+                self.retired_stacks.push(stack);
+            }
+            process.parent
+        };
+
+        // Orphans have nobody to wait for them
+        let orphans: Vec<Pid> = self.processes.values_mut()
+            .filter(|p| p.parent == Some(pid))
+            .map(|p| {
+                p.parent = None;
+                p.pid
+            })
+            .collect();
+        for orphan in orphans {
+            if !self.processes[&orphan].is_alive() {
+                self.processes.remove(&orphan);
+            }
+        }
+
+        let parent = match parent.and_then(|ppid| self.processes.get_mut(&ppid)) {
+            Some(parent) => parent,
+            None => {
+                self.processes.remove(&pid);
+                return;
+            }
+        };
+        if let ProcessState::Waiting(target) = parent.state {
+            if target.is_none() || target == Some(pid) {
+                // Finish the parent's wait for it
+                if let Some(frame) = parent.frame() {
+                    frame.set_arg(0, pid);
+                    frame.set_arg(1, code as usize);
+                }
+                parent.state = ProcessState::Ready;
+                let ppid = parent.pid;
+                self.ready.push_back(ppid);
+                self.processes.remove(&pid);
+            }
+        }
+    }
+
+    /** Collect an exited child (a specific one, or any), or block until one exits */
+    pub fn wait_current(&mut self, target: Option<Pid>) -> Result<WaitResult, WaitError> {
+        let pid = self.current.ok_or(WaitError::NoChild)?;
+        let mut children = self.processes.values()
+            .filter(|p| p.parent == Some(pid) && (target.is_none() || target == Some(p.pid)));
+        let mut any = false;
+        let mut exited = None;
+        for child in &mut children {
+            any = true;
+            if let ProcessState::Zombie(code) = child.state {
+                exited = Some((child.pid, code));
+                break;
+            }
+        }
+        if let Some((child, code)) = exited {
+            self.processes.remove(&child);
+            return Ok((child, code));
+        }
+        if !any {
+            return Err(WaitError::NoChild);
+        }
+        self.processes.get_mut(&pid).unwrap().state = ProcessState::Waiting(target);
+        self.need_resched = true;
+        return Err(WaitError::Blocked);
+    }
+
+    /** Kill the current process, e.g. for a fault it can't recover from. False if there isn't one. */
+    pub fn kill_current(&mut self, code: i32) -> bool {
+        if self.current.is_none() {
+            return false;
+        }
+        self.exit_current(code);
+        return true;
+    }
+
+    /** The first switch, from the boot stack */
+    fn start(&mut self) -> *mut TrapFrame {
+        self.need_resched = true;
+        return self.switch(None);
+    }
+
+    /**
+     * Called on the way out of every supervisor trap with the frame that trapped. Returns the frame to
+     * resume: the same one, or another process's if it's time to switch.
+     */
+    pub fn schedule(&mut self, frame: &TrapFrame) -> *mut TrapFrame {
+        if !self.need_resched {
+            return frame as *const TrapFrame as *mut TrapFrame;
+        }
+        // Only user processes and idle get switched away from; kernel code just carries on
+        if !frame.from_user() && self.current.is_some() {
+            return frame as *const TrapFrame as *mut TrapFrame;
+        }
+        return self.switch(Some(frame));
+    }
+
+    fn switch(&mut self, frame: Option<&TrapFrame>) -> *mut TrapFrame {
+        self.need_resched = false;
+        let running_on = frame.as_ref().map(|f| *f as *const TrapFrame as usize);
+
+        if let Some(process) = self.current_process() {
+            if process.state == ProcessState::Running {
+                process.state = ProcessState::Ready;
+                let pid = process.pid;
+                self.ready.push_back(pid);
+            }
+        }
+
+        let mut next = None;
+        while let Some(pid) = self.ready.pop_front() {
+            if self.processes.get(&pid).is_some_and(|p| p.state == ProcessState::Ready) {
+                next = Some(pid);
+                break;
+            }
+        }
+
+        let resume = match next {
+            Some(pid) => {
+                let process = self.processes.get_mut(&pid).unwrap();
+                process.state = ProcessState::Running;
+                let space = process.space.unwrap();
+                let resume = process.frame().unwrap() as *mut TrapFrame;
+                MMUManager::get_global(|mmu| mmu.enable(space));
+                self.current = Some(pid);
+                resume
+            }
+            None => {
+                self.current = None;
+                MMUManager::get_global(|mmu| mmu.enable(mmu.kernel_space_id()));
+                match frame {
+                    // Already idling
+                    Some(frame) if !frame.from_user() => frame as *const TrapFrame as *mut TrapFrame,
+                    _ => self.idle_frame()
+                }
+            }
+        };
+        self.slice_start = timer::ticks();
+        self.release_retired(running_on);
+        return resume;
+    }
+
+    /** A fresh frame at the top of the idle stack that resumes into idle() */
+    fn idle_frame(&mut self) -> *mut TrapFrame {
+        let top = self.idle_stack * PAGE_SIZE + KERNEL_STACK_SIZE;
+        let address = top - size_of::<TrapFrame>();
+        let frame = unsafe { &mut *(address as *mut TrapFrame) };
+        *frame = TrapFrame {
+            regs: [0; 32],
+            pc: idle as *const () as usize,
+            status: SSTATUS_SPP | SSTATUS_SPIE,
+            tval: 0,
+            cause: 0,
+        };
+        // Stack grows down from just under the frame
+        frame.regs[2] = address;
+        return frame;
+    }
+
+    /** Free what exited processes left behind, except the kernel stack we're still running on */
+    fn release_retired(&mut self, running_on: Option<usize>) {
+        for space in self.retired_spaces.drain(..) {
+            // Never the active space: we've just switched away from it
+            MMUManager::get_global(|mmu| mmu.destroy_address_space(space));
+        }
+        let in_use = |stack: usize| {
+            running_on.is_some_and(|address| address >= stack * PAGE_SIZE && address < stack * PAGE_SIZE + KERNEL_STACK_SIZE)
+        };
+        let (keep, free): (Vec<usize>, Vec<usize>) = self.retired_stacks.iter().partition(|stack| in_use(**stack));
+        for stack in free {
+            let _ = PageAllocator::get_global(|pg| pg.deallocate_order(stack, KERNEL_STACK_ORDER));
+        }
+        self.retired_stacks = keep;
+    }
+}
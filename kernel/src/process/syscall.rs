@@ -0,0 +1,117 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::fs::VfsError;
+use crate::loader::LoadError;
+use crate::mmu::page_tables::{MMUManager, PAGE_SIZE};
+use crate::mmu::vma::FaultAccess;
+use crate::process;
+use crate::process::scheduler::{Scheduler, WaitError};
+use crate::timer;
+use crate::trap::frame::TrapFrame;
+
+// Numbers follow Linux's RISC-V ones where there's an equivalent.
+// a7 is the number, a0-a5 the arguments, and the result comes back in a0 (negative for errors).
+pub const SYS_EXIT: usize = 93;
+/// a0: milliseconds to sleep for
+pub const SYS_SLEEP: usize = 101;
+pub const SYS_YIELD: usize = 124;
+pub const SYS_GETPID: usize = 172;
+/// a0: pid to wait for, or -1 for any child. Returns the pid in a0 and its exit code in a1.
+pub const SYS_WAIT: usize = 260;
+/**
+ * a0: address of a path, a1: its length in bytes. Runs that executable as a child of the caller, with the path
+ * as its only argument, and returns its pid. Linux has no equivalent, so this is numbered well clear of its calls.
+ */
+pub const SYS_SPAWN: usize = 1024;
+
+const ENOENT: isize = 2;
+const ENOEXEC: isize = 8;
+const ECHILD: isize = 10;
+const ENOMEM: isize = 12;
+const EFAULT: isize = 14;
+const ENAMETOOLONG: isize = 36;
+const ENOSYS: isize = 38;
+
+/// Longest path SYS_SPAWN will copy in
+const MAX_PATH: usize = 256;
+
+/** Handle an ecall from user mode. Always "handled": unknown calls just fail with ENOSYS. */
+pub fn handle(frame: &mut TrapFrame) -> bool {
+    // ecall is never compressed
+    frame.pc += 4;
+    let number = frame.regs[17];
+    let arg = frame.arg(0);
+    let result = match number {
+        SYS_EXIT => {
+            Scheduler::get_global(|scheduler| scheduler.exit_current(arg as i32));
+            0
+        }
+        SYS_SLEEP => {
+            let ticks = timer::ms_to_ticks(arg as u64);
+            Scheduler::get_global(|scheduler| scheduler.sleep_current(ticks));
+            0
+        }
+        SYS_YIELD => {
+            Scheduler::get_global(|scheduler| scheduler.yield_current());
+            0
+        }
+        SYS_GETPID => Scheduler::get_global(|scheduler| scheduler.current()).unwrap_or(0) as isize,
+        SYS_WAIT => {
+            let target = if arg as isize == -1 { None } else { Some(arg) };
+            match Scheduler::get_global(|scheduler| scheduler.wait_current(target)) {
+                Ok((pid, code)) => {
+                    frame.set_arg(1, code as usize);
+                    pid as isize
+                }
+                // exit_current fills in a0/a1 when the child is done
+                Err(WaitError::Blocked) => return true,
+                Err(WaitError::NoChild) => -ECHILD
+            }
+        }
+        SYS_SPAWN => spawn(arg, frame.arg(1)),
+        _ => -ENOSYS
+    };
+    frame.set_arg(0, result as usize);
+    return true;
+}
+
+fn spawn(address: usize, len: usize) -> isize {
+    if len > MAX_PATH {
+        return -ENAMETOOLONG;
+    }
+    let path = match read_user(address, len).and_then(|bytes| String::from_utf8(bytes).ok()) {
+        Some(path) => path,
+        None => return -EFAULT
+    };
+    let parent = Scheduler::get_global(|scheduler| scheduler.current());
+    return match process::spawn_path(&path, &[&path], &[], parent) {
+        Ok(pid) => pid as isize,
+        Err(LoadError::Io(VfsError::NotFound)) => -ENOENT,
+        Err(LoadError::OutOfMemory) | Err(LoadError::NoAddressSpace) => -ENOMEM,
+        Err(_) => -ENOEXEC
+    };
+}
+
+/** Copy from the current process's memory, backing lazy pages as a user read would. None if it can't read it all. */
+fn read_user(address: usize, len: usize) -> Option<Vec<u8>> {
+    let space_id = Scheduler::get_global(|scheduler| scheduler.current_process().and_then(|process| process.space))?;
+    address.checked_add(len)?;
+    return MMUManager::get_global(|mmu| {
+        let space = mmu.get_space(space_id)?;
+        let mut bytes = Vec::with_capacity(len);
+        while bytes.len() < len {
+            let vaddr = address + bytes.len();
+            let page = vaddr / PAGE_SIZE;
+            if !space.is_mapped(page) && space.handle_fault(mmu, page, FaultAccess::Read, true).is_err() {
+                return None;
+            }
+            let (paddr, permissions) = space.translate(vaddr)?;
+            if !permissions.user || !permissions.read {
+                return None;
+            }
+            let count = (PAGE_SIZE - vaddr % PAGE_SIZE).min(len - bytes.len());
+            bytes.extend_from_slice(unsafe { core::slice::from_raw_parts(paddr as *const u8, count) });
+        }
+        return Some(bytes);
+    });
+}
@@ -0,0 +1,234 @@
+#[cfg(not(test))]
+use core::arch::asm;
+use core::fmt::Write;
+use crate::mmu::page_tables::PAGE_SIZE;
+use crate::mmu::tlb;
+use crate::peripherals::basic_fifo::BasicFIFO;
+use crate::peripherals::stream::OutStream;
+use crate::sbi::*;
+use crate::timer;
+use crate::trap::frame::TrapFrame;
+
+/// Host unit tests can't assemble machine CSR accesses; the firmware never runs in them
+#[cfg(test)]
+macro_rules! asm {
+    ($($t:tt)*) => {{
+        unsafe fn host_test() -> ! { unreachable!("RISC-V assembly in a host test") }
+        host_test()
+    }}
+}
+
+/// SBI v1.0
+const SPEC_VERSION: usize = 1 << 24;
+/// Not a registered implementation ID; anything that cares will just see an unknown firmware
+const IMPL_ID: usize = 0x6F73_7276;
+const IMPL_VERSION: usize = 1;
+
+/// We only ever run on one hart
+const HART_ID: usize = 0;
+/// Bigger ranges just flush everything
+const MAX_FLUSH_PAGES: usize = 64;
+
+const MIP_SSIP: usize = 1 << 1;
+const HART_SUSPEND_RETENTIVE: usize = 0;
+const HART_SUSPEND_NON_RETENTIVE: usize = 0x8000_0000;
+
+/** Must run in machine mode. Lets supervisor mode take the software interrupts send_ipi raises. */
+pub fn init() {
+    unsafe {
+        asm!("csrs mideleg, {}", in(reg) MIP_SSIP);
+        asm!("csrs sie, {}", in(reg) MIP_SSIP);
+    }
+}
+
+/**
+ * An ecall from supervisor mode. The extension is in a7, the function in a6, and arguments in a0-a5;
+ * a0 gets the error code and a1 the value (legacy calls only return a0).
+ */
+pub fn handle_ecall(frame: &mut TrapFrame) -> bool {
+    // ecall is never compressed
+    frame.pc += 4;
+    let extension = frame.regs[17];
+    let function = frame.regs[16];
+    let args = [frame.arg(0), frame.arg(1), frame.arg(2), frame.arg(3), frame.arg(4), frame.arg(5)];
+
+    match extension {
+        EXT_LEGACY_SET_TIMER => {
+            timer::set_compare(args[0] as u64 | ((args[1] as u64) << 32));
+            frame.set_arg(0, 0);
+            return true;
+        }
+        EXT_LEGACY_CONSOLE_PUTCHAR => {
+            BasicFIFO::print_fifo().write(args[0] as u8);
+            frame.set_arg(0, 0);
+            return true;
+        }
+        EXT_LEGACY_CONSOLE_GETCHAR => {
+            // Nothing to read from
+            frame.set_arg(0, -1isize as usize);
+            return true;
+        }
+        _ => {}
+    }
+
+    let result = match extension {
+        EXT_BASE => base(function, &args),
+        EXT_TIME => time(function, &args),
+        EXT_IPI => ipi(function, &args),
+        EXT_RFENCE => rfence(function, &args),
+        EXT_HSM => hsm(function, &args),
+        EXT_SRST => srst(function, &args),
+        _ => Err(SbiError::NotSupported)
+    };
+    match result {
+        Ok(value) => {
+            frame.set_arg(0, 0);
+            frame.set_arg(1, value);
+        }
+        Err(e) => frame.set_arg(0, e.code() as usize),
+    }
+    return true;
+}
+
+fn base(function: usize, args: &[usize; 6]) -> Result<usize, SbiError> {
+    return match function {
+        0 => Ok(SPEC_VERSION),
+        1 => Ok(IMPL_ID),
+        2 => Ok(IMPL_VERSION),
+        3 => Ok(is_supported(args[0]) as usize),
+        4 => Ok(read_id_csr(0)),
+        5 => Ok(read_id_csr(1)),
+        6 => Ok(read_id_csr(2)),
+        _ => Err(SbiError::NotSupported)
+    };
+}
+
+fn is_supported(extension: usize) -> bool {
+    matches!(extension,
+        EXT_LEGACY_SET_TIMER | EXT_LEGACY_CONSOLE_PUTCHAR | EXT_LEGACY_CONSOLE_GETCHAR |
+        EXT_BASE | EXT_TIME | EXT_IPI | EXT_RFENCE | EXT_HSM | EXT_SRST)
+}
+
+/** mvendorid, marchid or mimpid */
+fn read_id_csr(which: usize) -> usize {
+    let value: usize;
+    unsafe {
+        match which {
+            0 => asm!("csrr {}, mvendorid", out(reg) value),
+            1 => asm!("csrr {}, marchid", out(reg) value),
+            _ => asm!("csrr {}, mimpid", out(reg) value),
+        }
+    }
+    return value;
+}
+
+fn time(function: usize, args: &[usize; 6]) -> Result<usize, SbiError> {
+    if function != 0 {
+        return Err(SbiError::NotSupported);
+    }
+    // RV32 passes the 64 bit time in a0 (low) and a1 (high)
+    timer::set_compare(args[0] as u64 | ((args[1] as u64) << 32));
+    return Ok(0);
+}
+
+/** Whether a hart mask includes us. Any other hart is an invalid parameter. */
+fn targets_this_hart(hart_mask: usize, hart_mask_base: usize) -> Result<bool, SbiError> {
+    // A base of -1 means every hart
+    if hart_mask_base == usize::MAX {
+        return Ok(true);
+    }
+    if hart_mask == 0 {
+        return Ok(false);
+    }
+    if hart_mask_base != HART_ID || hart_mask & !1 != 0 {
+        return Err(SbiError::InvalidParam);
+    }
+    return Ok(true);
+}
+
+fn ipi(function: usize, args: &[usize; 6]) -> Result<usize, SbiError> {
+    if function != 0 {
+        return Err(SbiError::NotSupported);
+    }
+    if targets_this_hart(args[0], args[1])? {
+        unsafe {
+            asm!("csrs mip, {}", in(reg) MIP_SSIP);
+        }
+    }
+    return Ok(0);
+}
+
+fn rfence(function: usize, args: &[usize; 6]) -> Result<usize, SbiError> {
+    if function > 2 {
+        // The hypervisor fences
+        return Err(SbiError::NotSupported);
+    }
+    if !targets_this_hart(args[0], args[1])? {
+        return Ok(0);
+    }
+    let (start, size, asid) = (args[2], args[3], args[4]);
+    let whole = (start == 0 && size == 0) || size == usize::MAX || size / PAGE_SIZE > MAX_FLUSH_PAGES;
+    match function {
+        0 => unsafe { asm!("fence.i") },
+        1 if whole => tlb::flush_all(),
+        2 if whole => tlb::flush_asid(asid),
+        _ => {
+            let first = start / PAGE_SIZE;
+            let last = start.saturating_add(size).div_ceil(PAGE_SIZE);
+            for page in first..last {
+                if function == 1 {
+                    tlb::flush_page_all_spaces(page);
+                } else {
+                    tlb::flush_page(asid, page);
+                }
+            }
+        }
+    }
+    return Ok(0);
+}
+
+fn hsm(function: usize, args: &[usize; 6]) -> Result<usize, SbiError> {
+    return match function {
+        // hart_start: we're the only hart, and we're already running
+        0 if args[0] == HART_ID => Err(SbiError::AlreadyAvailable),
+        0 => Err(SbiError::InvalidParam),
+        // hart_stop: there'd be nothing left to run
+        1 => Err(SbiError::Failed),
+        // hart_get_status
+        2 if args[0] == HART_ID => Ok(0),
+        2 => Err(SbiError::InvalidParam),
+        // hart_suspend
+        3 => match args[0] {
+            HART_SUSPEND_RETENTIVE => {
+                unsafe { riscv::asm::wfi(); }
+                Ok(0)
+            }
+            HART_SUSPEND_NON_RETENTIVE => Err(SbiError::NotSupported),
+            _ => Err(SbiError::InvalidParam)
+        },
+        _ => Err(SbiError::NotSupported)
+    };
+}
+
+fn srst(function: usize, args: &[usize; 6]) -> Result<usize, SbiError> {
+    if function != 0 {
+        return Err(SbiError::NotSupported);
+    }
+    let (reset_type, reason) = (args[0], args[1]);
+    return match reset_type {
+        0 => shutdown(reason),
+        // There's no way to reset the machine from in here
+        1 | 2 => Err(SbiError::NotSupported),
+        _ => Err(SbiError::InvalidParam)
+    };
+}
+
+/** Same way out as a panic: tell the host over the panic FIFO, then sit in ebreak */
+fn shutdown(reason: usize) -> ! {
+    let mut fifo = BasicFIFO::panic_fifo();
+    let _ = writeln!(fifo, "System shutdown requested (reason {})", reason);
+    fifo.write_ready();
+    loop {
+        unsafe { riscv::asm::ebreak(); }
+    }
+}
@@ -0,0 +1,238 @@
+pub mod firmware;
+
+#[cfg(not(test))]
+use core::arch::asm;
+use core::fmt;
+
+/// Host unit tests can't assemble an ecall, and nothing they run calls into the SBI
+#[cfg(test)]
+macro_rules! asm {
+    ($($t:tt)*) => {{
+        unsafe fn host_test() -> ! { unreachable!("RISC-V assembly in a host test") }
+        host_test()
+    }}
+}
+
+// Extension IDs
+pub const EXT_LEGACY_SET_TIMER: usize = 0x00;
+pub const EXT_LEGACY_CONSOLE_PUTCHAR: usize = 0x01;
+pub const EXT_LEGACY_CONSOLE_GETCHAR: usize = 0x02;
+pub const EXT_BASE: usize = 0x10;
+pub const EXT_TIME: usize = 0x5449_4D45;
+pub const EXT_IPI: usize = 0x0073_5049;
+pub const EXT_RFENCE: usize = 0x5246_4E43;
+pub const EXT_HSM: usize = 0x0048_534D;
+pub const EXT_SRST: usize = 0x5352_5354;
+
+// Error codes, as returned in a0
+const SBI_SUCCESS: isize = 0;
+const SBI_ERR_FAILED: isize = -1;
+const SBI_ERR_NOT_SUPPORTED: isize = -2;
+const SBI_ERR_INVALID_PARAM: isize = -3;
+const SBI_ERR_DENIED: isize = -4;
+const SBI_ERR_INVALID_ADDRESS: isize = -5;
+const SBI_ERR_ALREADY_AVAILABLE: isize = -6;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SbiError {
+    Failed,
+    NotSupported,
+    InvalidParam,
+    Denied,
+    InvalidAddress,
+    AlreadyAvailable,
+    Unknown(isize),
+}
+
+impl SbiError {
+    fn from_code(code: isize) -> SbiError {
+        match code {
+            SBI_ERR_FAILED => SbiError::Failed,
+            SBI_ERR_NOT_SUPPORTED => SbiError::NotSupported,
+            SBI_ERR_INVALID_PARAM => SbiError::InvalidParam,
+            SBI_ERR_DENIED => SbiError::Denied,
+            SBI_ERR_INVALID_ADDRESS => SbiError::InvalidAddress,
+            SBI_ERR_ALREADY_AVAILABLE => SbiError::AlreadyAvailable,
+            other => SbiError::Unknown(other),
+        }
+    }
+
+    pub fn code(&self) -> isize {
+        match self {
+            SbiError::Failed => SBI_ERR_FAILED,
+            SbiError::NotSupported => SBI_ERR_NOT_SUPPORTED,
+            SbiError::InvalidParam => SBI_ERR_INVALID_PARAM,
+            SbiError::Denied => SBI_ERR_DENIED,
+            SbiError::InvalidAddress => SBI_ERR_INVALID_ADDRESS,
+            SbiError::AlreadyAvailable => SBI_ERR_ALREADY_AVAILABLE,
+            SbiError::Unknown(code) => *code,
+        }
+    }
+}
+
+impl fmt::Display for SbiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SbiError::Failed => write!(f, "failed"),
+            SbiError::NotSupported => write!(f, "not supported"),
+            SbiError::InvalidParam => write!(f, "invalid parameter"),
+            SbiError::Denied => write!(f, "denied"),
+            SbiError::InvalidAddress => write!(f, "invalid address"),
+            SbiError::AlreadyAvailable => write!(f, "already available"),
+            SbiError::Unknown(code) => write!(f, "unknown error {}", code),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HartStatus {
+    Started,
+    Stopped,
+    StartPending,
+    StopPending,
+    Suspended,
+    SuspendPending,
+    ResumePending,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ResetReason {
+    None = 0,
+    SystemFailure = 1,
+}
+
+/** An SBI call from supervisor mode: a7 is the extension, a6 the function, a0/a1 come back as error/value */
+fn call(extension: usize, function: usize, args: [usize; 3]) -> Result<usize, SbiError> {
+    let (error, value): (isize, usize);
+    unsafe {
+        asm!("ecall",
+             inlateout("a0") args[0] => error,
+             inlateout("a1") args[1] => value,
+             in("a2") args[2],
+             in("a6") function,
+             in("a7") extension);
+    }
+    if error == SBI_SUCCESS {
+        return Ok(value);
+    }
+    return Err(SbiError::from_code(error));
+}
+
+/** Legacy (v0.1) calls only return a0 */
+fn legacy_call(extension: usize, arg0: usize, arg1: usize) -> isize {
+    let result: isize;
+    unsafe {
+        asm!("ecall",
+             inlateout("a0") arg0 => result,
+             in("a1") arg1,
+             in("a7") extension);
+    }
+    return result;
+}
+
+/** (major, minor) */
+pub fn spec_version() -> (usize, usize) {
+    let version = call(EXT_BASE, 0, [0; 3]).unwrap_or(0);
+    return ((version >> 24) & 0x7F, version & 0xFF_FFFF);
+}
+
+pub fn probe_extension(extension: usize) -> bool {
+    return call(EXT_BASE, 3, [extension, 0, 0]).is_ok_and(|available| available != 0);
+}
+
+/** Interrupt (as a supervisor timer interrupt) once `time` reaches `stime`. Clears any pending one. */
+pub fn set_timer(stime: u64) -> Result<(), SbiError> {
+    return call(EXT_TIME, 0, [stime as usize, (stime >> 32) as usize, 0]).map(|_| ());
+}
+
+/** Raise a supervisor software interrupt on the harts in `hart_mask` (bit n is hart hart_mask_base + n) */
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> Result<(), SbiError> {
+    return call(EXT_IPI, 0, [hart_mask, hart_mask_base, 0]).map(|_| ());
+}
+
+pub fn remote_fence_i(hart_mask: usize, hart_mask_base: usize) -> Result<(), SbiError> {
+    return call(EXT_RFENCE, 0, [hart_mask, hart_mask_base, 0]).map(|_| ());
+}
+
+/** sfence.vma over [start, start + size) on other harts. A size of usize::MAX means everything. */
+pub fn remote_sfence_vma(hart_mask: usize, hart_mask_base: usize, start: usize, size: usize) -> Result<(), SbiError> {
+    return call_rfence(1, hart_mask, hart_mask_base, start, size, 0);
+}
+
+pub fn remote_sfence_vma_asid(hart_mask: usize, hart_mask_base: usize, start: usize, size: usize, asid: usize) -> Result<(), SbiError> {
+    return call_rfence(2, hart_mask, hart_mask_base, start, size, asid);
+}
+
+/** RFENCE takes more arguments than call() passes */
+fn call_rfence(function: usize, hart_mask: usize, hart_mask_base: usize, start: usize, size: usize, asid: usize) -> Result<(), SbiError> {
+    let (error, _value): (isize, usize);
+    unsafe {
+        asm!("ecall",
+             inlateout("a0") hart_mask => error,
+             inlateout("a1") hart_mask_base => _value,
+             in("a2") start,
+             in("a3") size,
+             in("a4") asid,
+             in("a6") function,
+             in("a7") EXT_RFENCE);
+    }
+    if error == SBI_SUCCESS {
+        return Ok(());
+    }
+    return Err(SbiError::from_code(error));
+}
+
+pub fn hart_start(hart_id: usize, start_address: usize, opaque: usize) -> Result<(), SbiError> {
+    return call(EXT_HSM, 0, [hart_id, start_address, opaque]).map(|_| ());
+}
+
+/** Stop the calling hart. Only returns if it couldn't. */
+pub fn hart_stop() -> SbiError {
+    return call(EXT_HSM, 1, [0; 3]).err().unwrap_or(SbiError::Failed);
+}
+
+pub fn hart_get_status(hart_id: usize) -> Result<HartStatus, SbiError> {
+    let status = call(EXT_HSM, 2, [hart_id, 0, 0])?;
+    return match status {
+        0 => Ok(HartStatus::Started),
+        1 => Ok(HartStatus::Stopped),
+        2 => Ok(HartStatus::StartPending),
+        3 => Ok(HartStatus::StopPending),
+        4 => Ok(HartStatus::Suspended),
+        5 => Ok(HartStatus::SuspendPending),
+        6 => Ok(HartStatus::ResumePending),
+        _ => Err(SbiError::Failed)
+    };
+}
+
+/** Only returns if the reset didn't happen */
+pub fn system_reset(reset_type: ResetType, reason: ResetReason) -> SbiError {
+    return call(EXT_SRST, 0, [reset_type as usize, reason as usize, 0]).err().unwrap_or(SbiError::Failed);
+}
+
+/** Acknowledge a supervisor software interrupt (what send_ipi raises) */
+pub fn clear_ipi() {
+    unsafe {
+        asm!("csrc sip, {}", in(reg) 1 << 1);
+    }
+}
+
+pub fn console_putchar(c: u8) {
+    legacy_call(EXT_LEGACY_CONSOLE_PUTCHAR, c as usize, 0);
+}
+
+/** None if there's nothing to read */
+pub fn console_getchar() -> Option<u8> {
+    let c = legacy_call(EXT_LEGACY_CONSOLE_GETCHAR, 0, 0);
+    if c < 0 {
+        return None;
+    }
+    return Some(c as u8);
+}
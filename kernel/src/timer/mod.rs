@@ -0,0 +1,249 @@
+pub mod wheel;
+
+#[cfg(not(test))]
+use core::arch::asm;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use spin::Mutex;
+use crate::mmu::page_tables::MMUManager;
+use crate::peripherals::basic_fifo::BasicFIFO;
+use crate::peripherals::clint::Clint;
+use crate::process::scheduler::Scheduler;
+use crate::timer::wheel::{TimerAction, TimerWheel};
+use crate::sbi;
+use crate::trap;
+
+/// Host unit tests only cover the timer wheel, never the CSRs behind it
+#[cfg(test)]
+macro_rules! asm {
+    ($($t:tt)*) => {{
+        unsafe fn host_test() -> ! { unreachable!("RISC-V assembly in a host test") }
+        host_test()
+    }}
+}
+
+/// There's no device tree to ask, so assume QEMU virt's timebase
+pub const TIMEBASE_HZ: u64 = 10_000_000;
+pub const TICK_HZ: u64 = 100;
+const TICK_INTERVAL: u64 = TIMEBASE_HZ / TICK_HZ;
+
+/// How often the working-set scanner runs
+const SCAN_INTERVAL_TICKS: u64 = TICK_HZ;
+
+const MIP_STIP: usize = 1 << 5;
+const MIE_MTIE: usize = 1 << 7;
+const MCOUNTEREN_TM: usize = 1 << 1;
+/// menvcfg.STCE is bit 63, so bit 31 of the high half on RV32
+const MENVCFGH_STCE: usize = 1 << 31;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TimerSource {
+    /// No interrupts; time only moves if the time CSR works
+    None = 0,
+    /// Machine mode gets the CLINT's interrupt and passes it on; supervisor mode sets the next one with sbi::set_timer
+    Clint = 1,
+    /// Supervisor mode programs stimecmp itself, and gets real supervisor timer interrupts
+    Sstc = 2,
+}
+
+static SOURCE: AtomicU8 = AtomicU8::new(TimerSource::None as u8);
+/// Whether reading `time` works, or has to be faked from the tick count
+static TIME_CSR: AtomicBool = AtomicBool::new(false);
+/// No 64 bit atomics on RV32
+static TICKS: Mutex<u64> = Mutex::new(0);
+/// When the next tick is due. Kept here rather than read back, since time might not be readable.
+static NEXT_DEADLINE: Mutex<u64> = Mutex::new(0);
+
+/** Must run in machine mode, after trap::init (unimplemented CSRs and devices are probed for) */
+pub fn init() {
+    // Let S and U mode read time
+    trap::probe(|| unsafe { asm!("csrs mcounteren, {}", in(reg) MCOUNTEREN_TM) });
+    // Probe with single reads: a skipped read leaves garbage, which read_time_csr's retry loop could spin on forever
+    let time_csr = trap::probe(|| {
+        read_time();
+        read_timeh();
+    });
+    TIME_CSR.store(time_csr, Ordering::SeqCst);
+
+    let mut envcfg = 0;
+    let sstc = trap::probe(|| envcfg = unsafe { enable_stce() }) && envcfg & MENVCFGH_STCE != 0 && TIME_CSR.load(Ordering::SeqCst);
+    let clint = !sstc && trap::probe(|| { Clint::new().mtime_low(); });
+
+    let source = if sstc {
+        TimerSource::Sstc
+    } else if clint {
+        TimerSource::Clint
+    } else {
+        TimerSource::None
+    };
+    SOURCE.store(source as u8, Ordering::SeqCst);
+
+    if source != TimerSource::None {
+        unsafe {
+            asm!("csrs mideleg, {}", in(reg) MIP_STIP);
+            asm!("csrs sie, {}", in(reg) MIP_STIP);
+        }
+        let time = if sstc { read_time_csr() } else { Clint::new().mtime() };
+        *NEXT_DEADLINE.lock() = time + TICK_INTERVAL;
+        set_compare(time + TICK_INTERVAL);
+    }
+
+    let mut print_fifo = BasicFIFO::print_fifo();
+    let _ = writeln!(print_fifo, "Timer: {:?}, time CSR: {}", source, TIME_CSR.load(Ordering::SeqCst));
+}
+
+pub fn source() -> TimerSource {
+    match SOURCE.load(Ordering::SeqCst) {
+        1 => TimerSource::Clint,
+        2 => TimerSource::Sstc,
+        _ => TimerSource::None
+    }
+}
+
+/** Raw monotonic time, in TIMEBASE_HZ units */
+pub fn now() -> u64 {
+    if TIME_CSR.load(Ordering::Relaxed) {
+        return read_time_csr();
+    }
+    return ticks() * TICK_INTERVAL;
+}
+
+pub fn uptime_us() -> u64 {
+    return now() / (TIMEBASE_HZ / 1_000_000);
+}
+
+pub fn uptime_ms() -> u64 {
+    return now() / (TIMEBASE_HZ / 1_000);
+}
+
+/** Timer interrupts so far */
+pub fn ticks() -> u64 {
+    return *TICKS.lock();
+}
+
+/** Rounded up, so sleeping for any time at all takes at least a tick */
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    return (ms * TICK_HZ).div_ceil(1000);
+}
+
+/** A point in time to give up waiting at */
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Deadline(u64);
+
+impl Deadline {
+    pub fn after_ms(ms: u64) -> Deadline {
+        return Deadline(now() + ms * (TIMEBASE_HZ / 1_000));
+    }
+
+    pub fn expired(&self) -> bool {
+        return now() >= self.0;
+    }
+}
+
+/**
+ * Machine mode only: interrupt supervisor mode when time reaches `value`, and clear the pending one. This is
+ * what the firmware's set_timer does.
+ */
+pub fn set_compare(value: u64) {
+    unsafe {
+        match source() {
+            TimerSource::Sstc => set_stimecmp(value),
+            TimerSource::Clint => {
+                Clint::new().set_mtimecmp(value);
+                asm!("csrc mip, {}", in(reg) MIP_STIP);
+                asm!("csrs mie, {}", in(reg) MIE_MTIE);
+            }
+            TimerSource::None => {}
+        }
+    }
+}
+
+/**
+ * Machine timer interrupt: pass it on as a supervisor timer interrupt, and stay quiet until supervisor mode
+ * sets the next deadline
+ */
+pub fn machine_tick() {
+    unsafe {
+        asm!("csrc mie, {}", in(reg) MIE_MTIE);
+        asm!("csrs mip, {}", in(reg) MIP_STIP);
+    }
+}
+
+/** Supervisor timer interrupt: set up the next one, fire due timers, and let the scheduler preempt */
+pub fn supervisor_tick() {
+    let next = {
+        let mut deadline = NEXT_DEADLINE.lock();
+        *deadline += TICK_INTERVAL;
+        // If we've fallen well behind, skip ticks rather than taking them back to back
+        if TIME_CSR.load(Ordering::Relaxed) {
+            *deadline = (*deadline).max(read_time_csr() + 1);
+        }
+        *deadline
+    };
+    match source() {
+        // stimecmp is ours to write
+        TimerSource::Sstc => unsafe { set_stimecmp(next) },
+        _ => {
+            let _ = sbi::set_timer(next);
+        }
+    }
+
+    let tick = {
+        let mut ticks = TICKS.lock();
+        *ticks += 1;
+        *ticks
+    };
+
+    let due = TimerWheel::try_get_global(|wheel| wheel.advance(tick)).unwrap_or_default();
+    for action in due {
+        match action {
+            TimerAction::Wake(pid) => {
+                Scheduler::try_get_global(|scheduler| scheduler.wake(pid));
+            }
+            TimerAction::Call(f, arg) => f(arg),
+        }
+    }
+    Scheduler::try_get_global(|scheduler| scheduler.tick(tick));
+
+    if tick.is_multiple_of(SCAN_INTERVAL_TICKS) {
+        MMUManager::try_get_global(|mmu| mmu.scan_access());
+    }
+}
+
+fn read_time_csr() -> u64 {
+    // Re-read if the low half wrapped between reads
+    loop {
+        let high = read_timeh();
+        let low = read_time();
+        if read_timeh() == high {
+            return ((high as u64) << 32) | low as u64;
+        }
+    }
+}
+
+fn read_time() -> usize {
+    let value: usize;
+    unsafe { asm!("csrr {}, time", out(reg) value); }
+    return value;
+}
+
+fn read_timeh() -> usize {
+    let value: usize;
+    unsafe { asm!("csrr {}, timeh", out(reg) value); }
+    return value;
+}
+
+/** Ask for Sstc, returning what menvcfgh holds afterwards (STCE doesn't stick without it) */
+unsafe fn enable_stce() -> usize {
+    let envcfg: usize;
+    asm!("csrs menvcfgh, {}", in(reg) MENVCFGH_STCE);
+    asm!("csrr {}, menvcfgh", out(reg) envcfg);
+    return envcfg;
+}
+
+unsafe fn set_stimecmp(value: u64) {
+    // Same dance as the CLINT: don't let it dip below both the old and new values
+    asm!("csrw stimecmp, {}", in(reg) usize::MAX);
+    asm!("csrw stimecmph, {}", in(reg) (value >> 32) as usize);
+    asm!("csrw stimecmp, {}", in(reg) value as usize);
+}
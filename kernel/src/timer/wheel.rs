@@ -0,0 +1,194 @@
+use alloc::vec::Vec;
+use spin::RwLock;
+use crate::process::Pid;
+
+/// One slot per tick, wrapping. Timers further out than this just wait for later laps.
+const WHEEL_SLOTS: usize = 64;
+
+pub type TimerId = usize;
+
+#[derive(Copy, Clone, Debug)]
+pub enum TimerAction {
+    /// Make a sleeping process ready
+    Wake(Pid),
+    /// Call a function with an argument, for timeouts. Runs in the timer interrupt.
+    Call(fn(usize), usize),
+}
+
+struct Timer {
+    id: TimerId,
+    /// Tick to fire on
+    deadline: u64,
+    action: TimerAction,
+}
+
+/** Hashed timing wheel: adding and cancelling don't need to look at every pending timer */
+pub struct TimerWheel {
+    slots: Vec<Vec<Timer>>,
+    /// Every timer at or before this tick has fired
+    now: u64,
+    next_id: TimerId,
+}
+
+static GLOBAL_TIMER_WHEEL: RwLock<Option<TimerWheel>> = RwLock::new(None);
+
+impl TimerWheel {
+    /** Needs the heap */
+    pub fn create_global() {
+        let current = GLOBAL_TIMER_WHEEL.upgradeable_read();
+        if current.is_some() {
+            panic!("Global timer wheel already initialized")
+        }
+        let mut write = current.upgrade();
+        write.replace(TimerWheel::new());
+    }
+
+    fn new() -> TimerWheel {
+        TimerWheel {
+            slots: (0..WHEEL_SLOTS).map(|_| Vec::new()).collect(),
+            now: 0,
+            next_id: 0,
+        }
+    }
+
+    pub fn get_global<F, T>(f: F) -> T where F: Fn(&mut TimerWheel) -> T {
+        let mut lock = GLOBAL_TIMER_WHEEL.write();
+        let wheel = lock.as_mut().unwrap();
+        f(wheel)
+    }
+
+    /** None if there's no wheel yet, or it's in use */
+    pub fn try_get_global<F, T>(f: F) -> Option<T> where F: Fn(&mut TimerWheel) -> T {
+        let mut lock = GLOBAL_TIMER_WHEEL.try_write()?;
+        let wheel = lock.as_mut()?;
+        Some(f(wheel))
+    }
+}
+
+impl TimerWheel {
+    /** Fire `action` on tick `deadline`, or the next tick if that's already passed */
+    pub fn add(&mut self, deadline: u64, action: TimerAction) -> TimerId {
+        let deadline = deadline.max(self.now + 1);
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.slots[(deadline % WHEEL_SLOTS as u64) as usize].push(Timer { id, deadline, action });
+        return id;
+    }
+
+    /** Returns false if it already fired (or never existed) */
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        for slot in self.slots.iter_mut() {
+            if let Some(index) = slot.iter().position(|t| t.id == id) {
+                slot.swap_remove(index);
+                return true;
+            }
+        }
+        return false;
+    }
+
+    pub fn pending(&self) -> usize {
+        return self.slots.iter().map(|slot| slot.len()).sum();
+    }
+
+    /** Move time forward to `tick`, returning everything that's due, earliest first */
+    pub fn advance(&mut self, tick: u64) -> Vec<TimerAction> {
+        let mut due: Vec<Timer> = Vec::new();
+        if tick <= self.now {
+            return Vec::new();
+        }
+        // Each slot only needs looking at once, however far we've jumped
+        let steps = (tick - self.now).min(WHEEL_SLOTS as u64);
+        for step in 1..=steps {
+            let slot = &mut self.slots[((self.now + step) % WHEEL_SLOTS as u64) as usize];
+            let mut index = 0;
+            while index < slot.len() {
+                if slot[index].deadline <= tick {
+                    due.push(slot.swap_remove(index));
+                } else {
+                    index += 1;
+                }
+            }
+        }
+        self.now = tick;
+        due.sort_by_key(|t| t.deadline);
+        return due.into_iter().map(|t| t.action).collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn woken(actions: Vec<TimerAction>) -> Vec<Pid> {
+        actions.into_iter().map(|action| match action {
+            TimerAction::Wake(pid) => pid,
+            other => panic!("unexpected {:?}", other),
+        }).collect()
+    }
+
+    #[test]
+    fn fires_on_its_deadline() {
+        let mut wheel = TimerWheel::new();
+        wheel.add(3, TimerAction::Wake(1));
+        assert!(wheel.advance(2).is_empty());
+        assert_eq!(woken(wheel.advance(3)), vec![1]);
+        assert!(wheel.advance(4).is_empty());
+        assert_eq!(wheel.pending(), 0);
+    }
+
+    #[test]
+    fn past_deadlines_fire_next_tick() {
+        let mut wheel = TimerWheel::new();
+        wheel.advance(10);
+        wheel.add(5, TimerAction::Wake(1));
+        assert_eq!(woken(wheel.advance(11)), vec![1]);
+    }
+
+    #[test]
+    fn jumps_return_everything_due_in_order() {
+        let mut wheel = TimerWheel::new();
+        wheel.add(7, TimerAction::Wake(2));
+        wheel.add(2, TimerAction::Wake(1));
+        wheel.add(20, TimerAction::Wake(3));
+        assert_eq!(woken(wheel.advance(10)), vec![1, 2]);
+        assert_eq!(wheel.pending(), 1);
+    }
+
+    #[test]
+    fn timers_further_than_a_lap_wait_for_it() {
+        let mut wheel = TimerWheel::new();
+        let lap = WHEEL_SLOTS as u64;
+        // Same slot as tick 5, but a lap later
+        wheel.add(5 + lap, TimerAction::Wake(2));
+        wheel.add(5, TimerAction::Wake(1));
+        assert_eq!(woken(wheel.advance(5)), vec![1]);
+        assert!(wheel.advance(4 + lap).is_empty());
+        assert_eq!(woken(wheel.advance(5 + lap)), vec![2]);
+    }
+
+    #[test]
+    fn jumping_several_laps_still_fires() {
+        let mut wheel = TimerWheel::new();
+        let lap = WHEEL_SLOTS as u64;
+        wheel.add(3 * lap + 1, TimerAction::Wake(1));
+        assert_eq!(woken(wheel.advance(10 * lap)), vec![1]);
+    }
+
+    #[test]
+    fn cancelled_timers_never_fire() {
+        let mut wheel = TimerWheel::new();
+        let id = wheel.add(4, TimerAction::Wake(1));
+        wheel.add(4, TimerAction::Wake(2));
+        assert!(wheel.cancel(id));
+        assert!(!wheel.cancel(id));
+        assert_eq!(woken(wheel.advance(4)), vec![2]);
+    }
+
+    #[test]
+    fn fired_timers_cant_be_cancelled() {
+        let mut wheel = TimerWheel::new();
+        let id = wheel.add(1, TimerAction::Wake(1));
+        wheel.advance(1);
+        assert!(!wheel.cancel(id));
+    }
+}
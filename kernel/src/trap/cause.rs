@@ -0,0 +1,104 @@
+use core::fmt;
+
+const INTERRUPT_BIT: usize = 1 << 31;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Interrupt {
+    UserSoft,
+    SupervisorSoft,
+    MachineSoft,
+    UserTimer,
+    SupervisorTimer,
+    MachineTimer,
+    UserExternal,
+    SupervisorExternal,
+    MachineExternal,
+    Unknown(usize),
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Exception {
+    InstructionMisaligned,
+    InstructionFault,
+    IllegalInstruction,
+    Breakpoint,
+    LoadMisaligned,
+    LoadFault,
+    StoreMisaligned,
+    StoreFault,
+    UserEnvCall,
+    SupervisorEnvCall,
+    MachineEnvCall,
+    InstructionPageFault,
+    LoadPageFault,
+    StorePageFault,
+    Unknown(usize),
+}
+
+/** Decoded form of scause/mcause. The riscv crate's version is missing a few codes we care about. */
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TrapCause {
+    Interrupt(Interrupt),
+    Exception(Exception),
+}
+
+impl TrapCause {
+    pub fn from_bits(bits: usize) -> TrapCause {
+        let code = bits & !INTERRUPT_BIT;
+        if bits & INTERRUPT_BIT != 0 {
+            return TrapCause::Interrupt(match code {
+                0 => Interrupt::UserSoft,
+                1 => Interrupt::SupervisorSoft,
+                3 => Interrupt::MachineSoft,
+                4 => Interrupt::UserTimer,
+                5 => Interrupt::SupervisorTimer,
+                7 => Interrupt::MachineTimer,
+                8 => Interrupt::UserExternal,
+                9 => Interrupt::SupervisorExternal,
+                11 => Interrupt::MachineExternal,
+                _ => Interrupt::Unknown(code),
+            });
+        }
+        return TrapCause::Exception(match code {
+            0 => Exception::InstructionMisaligned,
+            1 => Exception::InstructionFault,
+            2 => Exception::IllegalInstruction,
+            3 => Exception::Breakpoint,
+            4 => Exception::LoadMisaligned,
+            5 => Exception::LoadFault,
+            6 => Exception::StoreMisaligned,
+            7 => Exception::StoreFault,
+            8 => Exception::UserEnvCall,
+            9 => Exception::SupervisorEnvCall,
+            11 => Exception::MachineEnvCall,
+            12 => Exception::InstructionPageFault,
+            13 => Exception::LoadPageFault,
+            15 => Exception::StorePageFault,
+            _ => Exception::Unknown(code),
+        });
+    }
+
+    /** Whether xtval holds a faulting address for this cause */
+    pub fn has_fault_address(&self) -> bool {
+        matches!(self, TrapCause::Exception(
+            Exception::InstructionMisaligned |
+            Exception::InstructionFault |
+            Exception::LoadMisaligned |
+            Exception::LoadFault |
+            Exception::StoreMisaligned |
+            Exception::StoreFault |
+            Exception::InstructionPageFault |
+            Exception::LoadPageFault |
+            Exception::StorePageFault
+        ))
+    }
+}
+
+impl fmt::Display for TrapCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrapCause::Interrupt(i) => write!(f, "interrupt {:?}", i),
+            TrapCause::Exception(e) => write!(f, "exception {:?}", e),
+        }
+    }
+}
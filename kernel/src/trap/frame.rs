@@ -0,0 +1,68 @@
+use core::fmt;
+use crate::trap::cause::TrapCause;
+
+pub const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+/** Register state saved by the trap vectors in trap.S. Layout must match the assembly. */
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct TrapFrame {
+    pub regs: [usize; 32],
+    /// sepc/mepc
+    pub pc: usize,
+    /// sstatus/mstatus
+    pub status: usize,
+    /// stval/mtval
+    pub tval: usize,
+    /// scause/mcause
+    pub cause: usize,
+}
+
+impl TrapFrame {
+    pub fn decode_cause(&self) -> TrapCause {
+        TrapCause::from_bits(self.cause)
+    }
+
+    /** sstatus.SPP clear: the trap came from user mode */
+    pub fn from_user(&self) -> bool {
+        self.status & (1 << 8) == 0
+    }
+
+    pub fn sp(&self) -> usize {
+        self.regs[2]
+    }
+
+    pub fn arg(&self, n: usize) -> usize {
+        self.regs[10 + n]
+    }
+
+    pub fn set_arg(&mut self, n: usize, value: usize) {
+        self.regs[10 + n] = value;
+    }
+
+    /** Step over the instruction at pc, accounting for compressed instructions */
+    pub fn skip_instruction(&mut self) {
+        let low_bits = unsafe { (self.pc as *const u16).read_volatile() } & 0b11;
+        self.pc += if low_bits == 0b11 { 4 } else { 2 };
+    }
+}
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "cause: {} ({:08x})", self.decode_cause(), self.cause)?;
+        writeln!(f, "pc: {:08x} status: {:08x} tval: {:08x}", self.pc, self.status, self.tval)?;
+        for row in 0..8 {
+            for col in 0..4 {
+                let reg = row * 4 + col;
+                write!(f, "{:>4}: {:08x} ", REGISTER_NAMES[reg], self.regs[reg])?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+pub mod cause;
+pub mod frame;
+
+#[cfg(not(test))]
+use core::arch::asm;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use riscv::register::{mscratch, mtvec, sscratch, stvec};
+use riscv::register::mtvec::TrapMode;
+use crate::mmu::page_tables::{MMUManager, PAGE_SIZE};
+use crate::mmu::vma::{FaultAccess, FaultError};
+use crate::peripherals::basic_fifo::BasicFIFO;
+use crate::kprintln;
+use crate::process::KILLED_EXIT_CODE;
+use crate::process::scheduler::Scheduler;
+use crate::process::syscall;
+use crate::sbi;
+use crate::sbi::firmware;
+use crate::timer;
+use crate::trap::cause::{Exception, Interrupt, TrapCause};
+use crate::trap::frame::TrapFrame;
+
+/// Host unit tests never install trap handlers, so none of this assembly runs there
+#[cfg(test)]
+macro_rules! asm {
+    ($($t:tt)*) => {{
+        unsafe fn host_test() -> ! { unreachable!("RISC-V assembly in a host test") }
+        host_test()
+    }}
+}
+
+extern "C" {
+    fn _supervisor_trap_vector();
+    fn _machine_trap_vector();
+    static _machine_trap_stack_top: u8;
+}
+
+/// Set while probe() is running
+static PROBING: AtomicBool = AtomicBool::new(false);
+static PROBE_FAULTED: AtomicBool = AtomicBool::new(false);
+
+/// Exceptions handed straight to supervisor mode. Breakpoints stay in machine mode, since the
+/// FIFOs use ebreak to poke the host, and S-mode ecalls go to machine mode on purpose.
+const DELEGATED_EXCEPTIONS: usize = (1 << 0) | // instruction misaligned
+    (1 << 1) | // instruction fault
+    (1 << 2) | // illegal instruction
+    (1 << 4) | // load misaligned
+    (1 << 5) | // load fault
+    (1 << 6) | // store misaligned
+    (1 << 7) | // store fault
+    (1 << 8) | // user ecall
+    (1 << 12) | // instruction page fault
+    (1 << 13) | // load page fault
+    (1 << 15); // store page fault
+
+/** Must run in machine mode */
+pub fn init() {
+    unsafe {
+        mtvec::write(_machine_trap_vector as *const () as usize, TrapMode::Direct);
+        mscratch::write(&_machine_trap_stack_top as *const u8 as usize);
+
+        stvec::write(_supervisor_trap_vector as *const () as usize, TrapMode::Direct);
+        // 0 means "trapped from supervisor mode" to the vector
+        sscratch::write(0);
+
+        asm!("csrw medeleg, {}", in(reg) DELEGATED_EXCEPTIONS);
+    }
+}
+
+/** Returns the frame for trap.S to resume, which is a different one after a context switch */
+#[no_mangle]
+extern "C" fn supervisor_trap_handler(frame: &mut TrapFrame) -> *mut TrapFrame {
+    let handled = match frame.decode_cause() {
+        TrapCause::Exception(Exception::Breakpoint) => handle_breakpoint(frame),
+        TrapCause::Exception(Exception::InstructionPageFault) |
+        TrapCause::Exception(Exception::LoadPageFault) |
+        TrapCause::Exception(Exception::StorePageFault) => handle_page_fault(frame),
+        TrapCause::Exception(Exception::UserEnvCall) => handle_ecall(frame),
+        TrapCause::Interrupt(interrupt) => handle_interrupt(frame, interrupt),
+        _ => false
+    };
+
+    if !handled {
+        // A process doing something bad only takes itself down
+        let killed = frame.from_user()
+            && Scheduler::get_global(|scheduler| scheduler.kill_current(KILLED_EXIT_CODE));
+        if !killed {
+            unhandled_trap(frame, "supervisor");
+        }
+        kprintln!("Killed process: {} at {:08x}", frame.decode_cause(), frame.pc);
+    }
+
+    return match Scheduler::try_get_global(|scheduler| scheduler.schedule(frame)) {
+        Some(resume) => resume,
+        None => frame
+    };
+}
+
+#[no_mangle]
+extern "C" fn machine_trap_handler(frame: &mut TrapFrame) {
+    let handled = match frame.decode_cause() {
+        TrapCause::Exception(Exception::Breakpoint) => handle_breakpoint(frame),
+        TrapCause::Exception(Exception::IllegalInstruction) |
+        TrapCause::Exception(Exception::LoadFault) |
+        TrapCause::Exception(Exception::StoreFault) if PROBING.load(Ordering::SeqCst) => handle_probe_fault(frame),
+        TrapCause::Interrupt(Interrupt::MachineTimer) => {
+            timer::machine_tick();
+            true
+        }
+        TrapCause::Exception(Exception::SupervisorEnvCall) => firmware::handle_ecall(frame),
+        _ => false
+    };
+
+    if !handled {
+        unhandled_trap(frame, "machine");
+    }
+}
+
+/**
+ * Machine mode only: run `f`, skipping any instruction in it that faults (an unimplemented CSR, a device
+ * that isn't there). Returns false if anything faulted.
+ */
+pub fn probe<F>(f: F) -> bool where F: FnOnce() {
+    PROBE_FAULTED.store(false, Ordering::SeqCst);
+    PROBING.store(true, Ordering::SeqCst);
+    f();
+    PROBING.store(false, Ordering::SeqCst);
+    return !PROBE_FAULTED.load(Ordering::SeqCst);
+}
+
+fn handle_probe_fault(frame: &mut TrapFrame) -> bool {
+    PROBE_FAULTED.store(true, Ordering::SeqCst);
+    frame.skip_instruction();
+    return true;
+}
+
+fn handle_breakpoint(frame: &mut TrapFrame) -> bool {
+    // Nothing to debug with; just keep going
+    frame.skip_instruction();
+    return true;
+}
+
+fn handle_page_fault(frame: &mut TrapFrame) -> bool {
+    let access = match frame.decode_cause() {
+        TrapCause::Exception(Exception::InstructionPageFault) => FaultAccess::Execute,
+        TrapCause::Exception(Exception::LoadPageFault) => FaultAccess::Read,
+        _ => FaultAccess::Write
+    };
+    let page = frame.tval / PAGE_SIZE;
+    let result = MMUManager::try_get_global(|mmu| {
+        match mmu.active_space() {
+            Some(space) => space.handle_fault(mmu, page, access, frame.from_user()),
+            None => Err(FaultError::Unmapped)
+        }
+    });
+    match result {
+        Some(Ok(())) => return true,
+        Some(Err(e)) => kprintln!("Page fault at {:08x}: {}", frame.tval, e),
+        // Translation is only on once the manager exists, so the lock is held by whatever we interrupted.
+        // Waiting for it would never return.
+        None => panic!("Page fault at {:08x} while the MMU lock is held: user, lazy and copy-on-write pages \
+                        can't be touched under MMUManager::get_global", frame.tval)
+    }
+    return false;
+}
+
+fn handle_ecall(frame: &mut TrapFrame) -> bool {
+    return syscall::handle(frame);
+}
+
+fn handle_interrupt(frame: &mut TrapFrame, interrupt: Interrupt) -> bool {
+    match interrupt {
+        Interrupt::SupervisorTimer => {
+            timer::supervisor_tick();
+            return true;
+        }
+        Interrupt::SupervisorSoft => {
+            // An IPI. There's only one hart, so nobody sends these for anything yet.
+            sbi::clear_ipi();
+            return true;
+        }
+        _ => return false
+    }
+}
+
+fn unhandled_trap(frame: &TrapFrame, mode: &str) -> ! {
+    let mut fifo = BasicFIFO::panic_fifo();
+    let cause = frame.decode_cause();
+    let _ = writeln!(fifo, "Unhandled {} trap", mode);
+    let _ = write!(fifo, "{}", frame);
+
+    if cause.has_fault_address() {
+        let _ = writeln!(fifo, "Faulting address: {:08x}", frame.tval);
+        let page = frame.tval / PAGE_SIZE;
+        match MMUManager::try_get_global(|mmu| mmu.active_space().map(|space| space.lookup(page))) {
+            Some(Some(Some(mapping))) => {
+                let _ = writeln!(fifo, "Mapping: {:x?}", mapping);
+            }
+            Some(Some(None)) => {
+                let _ = writeln!(fifo, "Mapping: page {:x} is not mapped", page);
+            }
+            Some(None) => {
+                let _ = writeln!(fifo, "Mapping: translation is off");
+            }
+            None => {
+                let _ = writeln!(fifo, "Mapping: MMU manager unavailable");
+            }
+        }
+    }
+
+    panic!("Unhandled {} trap: {} at {:08x}", mode, cause, frame.pc)
+}